@@ -1,3 +1,7 @@
+use std::{collections::BTreeMap, path::PathBuf, time::Duration};
+
+use crate::anchor::Bookmarks;
+
 #[derive(Debug, Clone)]
 pub enum Action {
     Quit,
@@ -5,8 +9,10 @@ pub enum Action {
     BufferSave,
     BufferReload,
     BufferUndo,
+    BufferFilter(ExternalCommandAction),
     CursorAnchor,
     CursorJump,
+    CursorJumpForward,
     CursorUp,
     CursorDown,
     CursorLeft,
@@ -28,22 +34,65 @@ pub enum Action {
     CharDeleteBackward,
     CharDeleteForward,
     LineDelete,
+    QueryWordLeft,
+    QueryWordRight,
+    QueryWordDeleteBackward,
+    QueryWordDeleteForward,
+    QueryKill,
+    QueryTranspose,
     MarkSet,
     MarkCopy,
     MarkCut,
+    RectMarkSet,
+    RectCopy,
+    RectCut,
+    RectPaste,
+    RectInsert,
     ClipboardPaste,
     ShellCommand(ExternalCommandAction),
-    Command(mame::command::ExternalCommand),
+    Command(CommandAction),
+    PtyCommand(PtyCommandAction),
+    OutputClose,
+    ErrorList(ErrorListAction),
+    ErrorNext,
+    ErrorPrev,
     Grep(GrepAction),
     GrepNextHit,
     GrepPrevHit,
+    GrepFirstHit,
+    GrepLastHit,
     GrepNextQuery,
     GrepPrevQuery,
+    GrepYankWord,
+    GrepResultsOpen,
+    GrepCancelSearch,
     GrepReplaceHit,
+    GrepReplaceAll,
+    GrepReplaceConfirm,
+    GrepConfirmYes,
+    GrepConfirmNo,
+    GrepConfirmAll,
+    GrepConfirmQuit,
+    MultiCursorFromHits,
+    MultiCursorClear,
     Echo(EchoAction),
+    Prompt(PromptAction),
+    PromptSubmit,
+    PromptCancel,
+    PromptPrevInput,
+    PromptNextInput,
     GotoLine,
-    FilePreviewOpen(mame::preview::FilePreviewSpec),
+    GotoCursorLine,
+    Goto(GotoAction),
+    TextInsert(TextInsertAction),
+    BookmarkSet(BookmarkAction),
+    BookmarkJump(BookmarkAction),
+    BookmarkList,
+    FilePreviewOpen(FilePreviewAction),
     FilePreviewClose,
+    PreviewScrollUp,
+    PreviewScrollDown,
+    PreviewCopyLine,
     Multiple(Vec<Action>),
 }
 
@@ -65,8 +114,21 @@ impl<'text, 'raw> TryFrom<nojson::RawJsonValue<'text, 'raw>> for Action {
             "buffer-save" => Ok(Self::BufferSave),
             "buffer-reload" => Ok(Self::BufferReload),
             "buffer-undo" => Ok(Self::BufferUndo),
+            "buffer-filter" => {
+                // Pipes the whole buffer through the command, replacing it with the output
+                for key in ["stdin", "output"] {
+                    if let Some(member) = value.to_member(key)?.map(Ok)? {
+                        return Err(member.invalid(format!("buffer-filter does not take {key:?}")));
+                    }
+                }
+                let mut action = ExternalCommandAction::try_from(value)?;
+                action.stdin = ExternalCommandStdin::Buffer;
+                action.output = ExternalCommandOutput::ReplaceBuffer;
+                Ok(Self::BufferFilter(action))
+            }
             "cursor-anchor" => Ok(Self::CursorAnchor),
             "cursor-jump" => Ok(Self::CursorJump),
+            "cursor-jump-forward" => Ok(Self::CursorJumpForward),
             "cursor-up" => Ok(Self::CursorUp),
             "cursor-down" => Ok(Self::CursorDown),
             "cursor-left" => Ok(Self::CursorLeft),
@@ -88,24 +150,65 @@ impl<'text, 'raw> TryFrom<nojson::RawJsonValue<'text, 'raw>> for Action {
             "char-delete-backward" => Ok(Self::CharDeleteBackward),
             "char-delete-forward" => Ok(Self::CharDeleteForward),
             "line-delete" => Ok(Self::LineDelete),
+            "query-word-left" => Ok(Self::QueryWordLeft),
+            "query-word-right" => Ok(Self::QueryWordRight),
+            "query-word-delete-backward" => Ok(Self::QueryWordDeleteBackward),
+            "query-word-delete-forward" => Ok(Self::QueryWordDeleteForward),
+            "query-kill" => Ok(Self::QueryKill),
+            "query-transpose" => Ok(Self::QueryTranspose),
             "mark-set" => Ok(Self::MarkSet),
             "mark-copy" => Ok(Self::MarkCopy),
             "mark-cut" => Ok(Self::MarkCut),
+            "rect-mark-set" => Ok(Self::RectMarkSet),
+            "rect-copy" => Ok(Self::RectCopy),
+            "rect-cut" => Ok(Self::RectCut),
+            "rect-paste" => Ok(Self::RectPaste),
+            "rect-insert" => Ok(Self::RectInsert),
             "clipboard-paste" => Ok(Self::ClipboardPaste),
             "echo" => EchoAction::try_from(value).map(Self::Echo),
+            "prompt" => PromptAction::try_from(value).map(Self::Prompt),
+            "prompt-submit" => Ok(Self::PromptSubmit),
+            "prompt-cancel" => Ok(Self::PromptCancel),
+            "prompt-prev-input" => Ok(Self::PromptPrevInput),
+            "prompt-next-input" => Ok(Self::PromptNextInput),
             "external-command" => ExternalCommandAction::try_from(value).map(Self::ShellCommand),
-            "command" => mame::command::ExternalCommand::try_from(value).map(Self::Command),
+            "command" => CommandAction::try_from(value).map(Self::Command),
+            "pty-command" => PtyCommandAction::try_from(value).map(Self::PtyCommand),
+            "output-close" => Ok(Self::OutputClose),
+            "error-list" => ErrorListAction::try_from(value).map(Self::ErrorList),
+            "error-next" => Ok(Self::ErrorNext),
+            "error-prev" => Ok(Self::ErrorPrev),
             "grep" => GrepAction::try_from(value).map(Self::Grep),
             "grep-next-hit" => Ok(Self::GrepNextHit),
             "grep-prev-hit" => Ok(Self::GrepPrevHit),
+            "grep-first-hit" => Ok(Self::GrepFirstHit),
+            "grep-last-hit" => Ok(Self::GrepLastHit),
             "grep-next-query" => Ok(Self::GrepNextQuery),
             "grep-prev-query" => Ok(Self::GrepPrevQuery),
+            "grep-yank-word" => Ok(Self::GrepYankWord),
+            "grep-results-open" => Ok(Self::GrepResultsOpen),
+            "grep-cancel-search" => Ok(Self::GrepCancelSearch),
             "grep-replace-hit" => Ok(Self::GrepReplaceHit),
+            "grep-replace-all" => Ok(Self::GrepReplaceAll),
+            "grep-replace-confirm" => Ok(Self::GrepReplaceConfirm),
+            "grep-confirm-yes" => Ok(Self::GrepConfirmYes),
+            "grep-confirm-no" => Ok(Self::GrepConfirmNo),
+            "grep-confirm-all" => Ok(Self::GrepConfirmAll),
+            "grep-confirm-quit" => Ok(Self::GrepConfirmQuit),
+            "multi-cursor-from-hits" => Ok(Self::MultiCursorFromHits),
+            "multi-cursor-clear" => Ok(Self::MultiCursorClear),
             "goto-line" => Ok(Self::GotoLine),
-            "file-preview-open" => {
-                mame::preview::FilePreviewSpec::try_from(value).map(Self::FilePreviewOpen)
-            }
+            "goto-cursor-line" => Ok(Self::GotoCursorLine),
+            "goto" => GotoAction::try_from(value).map(Self::Goto),
+            "text-insert" => TextInsertAction::try_from(value).map(Self::TextInsert),
+            "bookmark-set" => BookmarkAction::try_from(value).map(Self::BookmarkSet),
+            "bookmark-jump" => BookmarkAction::try_from(value).map(Self::BookmarkJump),
+            "bookmark-list" => Ok(Self::BookmarkList),
+            "file-preview-open" => FilePreviewAction::try_from(value).map(Self::FilePreviewOpen),
             "file-preview-close" => Ok(Self::FilePreviewClose),
+            "preview-scroll-up" => Ok(Self::PreviewScrollUp),
+            "preview-scroll-down" => Ok(Self::PreviewScrollDown),
+            "preview-copy-line" => Ok(Self::PreviewCopyLine),
             ty => Err(value.invalid(format!("unknown command type: {ty:?}"))),
         }
     }
@@ -114,7 +217,7 @@ impl<'text, 'raw> TryFrom<nojson::RawJsonValue<'text, 'raw>> for Action {
 #[derive(Debug, Clone)]
 pub enum ExternalCommandArg {
     Literal(String),
-    CurrentFile,
+    Var(CommandVar),
 }
 
 impl<'text, 'raw> TryFrom<nojson::RawJsonValue<'text, 'raw>> for ExternalCommandArg {
@@ -123,20 +226,215 @@ impl<'text, 'raw> TryFrom<nojson::RawJsonValue<'text, 'raw>> for ExternalCommand
     fn try_from(value: nojson::RawJsonValue<'text, 'raw>) -> Result<Self, Self::Error> {
         if let Ok(var) = value.to_member("var") {
             let var = var.required()?;
-            match var.to_unquoted_string_str()?.as_ref() {
-                "CURRENT_FILE" => Ok(Self::CurrentFile),
-                _ => Err(var.invalid("unknown var")),
-            }
+            let name = var.to_unquoted_string_str()?;
+            CommandVar::ALL
+                .into_iter()
+                .find(|v| v.name() == name)
+                .map(Self::Var)
+                .ok_or_else(|| var.invalid("unknown var"))
         } else {
             Ok(Self::Literal(value.try_into()?))
         }
     }
 }
 
+/// Editor state passed to external commands, as `{"var": NAME}` args and `KK_{NAME}` environment variables.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandVar {
+    CurrentFile,
+    Prompt,
+    CursorLine,   // 1-based
+    CursorColumn, // 1-based, in chars
+    Word,
+    MarkStart, // "LINE:CHAR", or empty if the mark is not set
+    MarkEnd,
+    Context,
+    ClipboardFile,
+    GrepQuery,
+    Dirty, // "1" if the buffer has unsaved changes, "0" otherwise
+}
+
+impl CommandVar {
+    pub const ALL: [Self; 11] = [
+        Self::CurrentFile,
+        Self::Prompt,
+        Self::CursorLine,
+        Self::CursorColumn,
+        Self::Word,
+        Self::MarkStart,
+        Self::MarkEnd,
+        Self::Context,
+        Self::ClipboardFile,
+        Self::GrepQuery,
+        Self::Dirty,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::CurrentFile => "CURRENT_FILE",
+            Self::Prompt => "PROMPT",
+            Self::CursorLine => "CURSOR_LINE",
+            Self::CursorColumn => "CURSOR_COLUMN",
+            Self::Word => "WORD",
+            Self::MarkStart => "MARK_START",
+            Self::MarkEnd => "MARK_END",
+            Self::Context => "CONTEXT",
+            Self::ClipboardFile => "CLIPBOARD_FILE",
+            Self::GrepQuery => "GREP_QUERY",
+            Self::Dirty => "DIRTY",
+        }
+    }
+}
+
+/// `mame`'s command, with args that may also be `{"var": NAME}` like `external-command`.
+#[derive(Debug, Clone)]
+pub struct CommandAction {
+    pub command: mame::command::ExternalCommand, // `args` are filled in when executed
+    pub args: Vec<ExternalCommandArg>,
+    pub env: CommandEnv, // "env" is merged into `command.envs`
+    pub timeout: Option<Duration>,
+}
+
+impl<'text, 'raw> TryFrom<nojson::RawJsonValue<'text, 'raw>> for CommandAction {
+    type Error = nojson::JsonParseError;
+
+    fn try_from(value: nojson::RawJsonValue<'text, 'raw>) -> Result<Self, Self::Error> {
+        Ok(Self {
+            command: parse_mame_command(value)?,
+            args: value
+                .to_member("args")?
+                .map(Vec::try_from)?
+                .unwrap_or_default(),
+            env: CommandEnv::try_from(value)?,
+            timeout: parse_timeout(value)?,
+        })
+    }
+}
+
+// Parses the members of mame's command other than "args", which are parsed by `CommandAction`
+// as mame does not accept `{"var": NAME}`.
+fn parse_mame_command(
+    value: nojson::RawJsonValue<'_, '_>,
+) -> Result<mame::command::ExternalCommand, nojson::JsonParseError> {
+    Ok(mame::command::ExternalCommand {
+        command: mame::json::parse_from_flattened_string(value.to_member("command")?.required()?)?,
+        args: Vec::new(),
+        envs: value
+            .to_member("envs")?
+            .map(|v| {
+                v.to_object()?
+                    .map(|(k, v)| Ok((k.try_into()?, mame::json::parse_from_flattened_string(v)?)))
+                    .collect()
+            })?
+            .unwrap_or_default(),
+        stdin: value
+            .to_member("stdin")?
+            .map(TryFrom::try_from)?
+            .unwrap_or_default(),
+        stdout: value
+            .to_member("stdout")?
+            .map(TryFrom::try_from)?
+            .unwrap_or_default(),
+        stderr: value
+            .to_member("stderr")?
+            .map(TryFrom::try_from)?
+            .unwrap_or_default(),
+    })
+}
+
+/// Working directory and additional environment variables of a command.
+#[derive(Debug, Clone, Default)]
+pub struct CommandEnv {
+    pub cwd: Option<CommandCwd>, // kk's current directory if omitted
+    pub env: BTreeMap<String, String>,
+}
+
+impl<'text, 'raw> TryFrom<nojson::RawJsonValue<'text, 'raw>> for CommandEnv {
+    type Error = nojson::JsonParseError;
+
+    fn try_from(value: nojson::RawJsonValue<'text, 'raw>) -> Result<Self, Self::Error> {
+        Ok(Self {
+            cwd: value.to_member("cwd")?.map(CommandCwd::try_from)?,
+            env: value
+                .to_member("env")?
+                .map(|v| {
+                    v.to_object()?
+                        .map(|(k, v)| {
+                            Ok((k.try_into()?, mame::json::flatten_string(v)?.into_owned()))
+                        })
+                        .collect()
+                })?
+                .unwrap_or_default(),
+        })
+    }
+}
+
+/// `mame`'s file preview, whose files may be relative to `"cwd"` like a command's output files.
+#[derive(Debug, Clone)]
+pub struct FilePreviewAction {
+    pub spec: mame::preview::FilePreviewSpec,
+    pub cwd: Option<CommandCwd>,
+}
+
+impl<'text, 'raw> TryFrom<nojson::RawJsonValue<'text, 'raw>> for FilePreviewAction {
+    type Error = nojson::JsonParseError;
+
+    fn try_from(value: nojson::RawJsonValue<'text, 'raw>) -> Result<Self, Self::Error> {
+        Ok(Self {
+            spec: value.try_into()?,
+            cwd: value.to_member("cwd")?.map(CommandCwd::try_from)?,
+        })
+    }
+}
+
+/// `"cwd"` of a command: a path, `{"var": "CURRENT_FILE_DIR"}` or `{"var": "PROJECT_ROOT"}`.
+#[derive(Debug, Clone)]
+pub enum CommandCwd {
+    Path(PathBuf),
+    CurrentFileDir,
+    ProjectRoot { markers: Vec<String> }, // Nearest ancestor of the current file containing any marker
+}
+
+impl<'text, 'raw> TryFrom<nojson::RawJsonValue<'text, 'raw>> for CommandCwd {
+    type Error = nojson::JsonParseError;
+
+    fn try_from(value: nojson::RawJsonValue<'text, 'raw>) -> Result<Self, Self::Error> {
+        let Ok(var) = value.to_member("var") else {
+            return Ok(Self::Path(PathBuf::from(
+                mame::json::flatten_string(value)?.into_owned(),
+            )));
+        };
+        let var = var.required()?;
+        match var.to_unquoted_string_str()?.as_ref() {
+            "CURRENT_FILE_DIR" => Ok(Self::CurrentFileDir),
+            "PROJECT_ROOT" => Ok(Self::ProjectRoot {
+                markers: value
+                    .to_member("markers")?
+                    .map(Vec::try_from)?
+                    .unwrap_or_else(|| vec!["Cargo.toml".to_owned(), ".git".to_owned()]),
+            }),
+            _ => Err(var.invalid("unknown cwd var")),
+        }
+    }
+}
+
+fn parse_timeout(
+    value: nojson::RawJsonValue<'_, '_>,
+) -> Result<Option<Duration>, nojson::JsonParseError> {
+    Ok(value
+        .to_member("timeout_ms")?
+        .map(u64::try_from)?
+        .map(Duration::from_millis))
+}
+
 #[derive(Debug, Clone)]
 pub struct ExternalCommandAction {
     pub command: String,
     pub args: Vec<ExternalCommandArg>,
+    pub stdin: ExternalCommandStdin,
+    pub output: ExternalCommandOutput,
+    pub env: CommandEnv,
+    pub timeout: Option<Duration>,
 }
 
 impl<'text, 'raw> TryFrom<nojson::RawJsonValue<'text, 'raw>> for ExternalCommandAction {
@@ -149,6 +447,159 @@ impl<'text, 'raw> TryFrom<nojson::RawJsonValue<'text, 'raw>> for ExternalCommand
                 .to_member("args")?
                 .map(Vec::try_from)?
                 .unwrap_or_default(),
+            stdin: value
+                .to_member("stdin")?
+                .map(ExternalCommandStdin::try_from)?
+                .unwrap_or(ExternalCommandStdin::Mark),
+            output: value
+                .to_member("output")?
+                .map(ExternalCommandOutput::try_from)?
+                .unwrap_or(ExternalCommandOutput::ReplaceMark),
+            env: CommandEnv::try_from(value)?,
+            timeout: parse_timeout(value)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExternalCommandStdin {
+    Mark, // The marked text, or nothing if the mark is not set
+    Buffer,
+    Line,
+    None,
+}
+
+impl<'text, 'raw> TryFrom<nojson::RawJsonValue<'text, 'raw>> for ExternalCommandStdin {
+    type Error = nojson::JsonParseError;
+
+    fn try_from(value: nojson::RawJsonValue<'text, 'raw>) -> Result<Self, Self::Error> {
+        match value.to_unquoted_string_str()?.as_ref() {
+            "mark" => Ok(Self::Mark),
+            "buffer" => Ok(Self::Buffer),
+            "line" => Ok(Self::Line),
+            "none" => Ok(Self::None),
+            _ => Err(value.invalid("unknown stdin")),
+        }
+    }
+}
+
+/// Where the stdout of an external command goes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExternalCommandOutput {
+    ReplaceMark, // Replaces the marked text, or inserts at the cursor if the mark is not set
+    Insert,
+    ReplaceBuffer,
+    Message,
+    Clipboard,
+    Preview, // Shows stdout and stderr side by side
+    Discard,
+}
+
+impl<'text, 'raw> TryFrom<nojson::RawJsonValue<'text, 'raw>> for ExternalCommandOutput {
+    type Error = nojson::JsonParseError;
+
+    fn try_from(value: nojson::RawJsonValue<'text, 'raw>) -> Result<Self, Self::Error> {
+        match value.to_unquoted_string_str()?.as_ref() {
+            "replace-mark" => Ok(Self::ReplaceMark),
+            "insert" => Ok(Self::Insert),
+            "replace-buffer" => Ok(Self::ReplaceBuffer),
+            "message" => Ok(Self::Message),
+            "clipboard" => Ok(Self::Clipboard),
+            "preview" => Ok(Self::Preview),
+            "discard" => Ok(Self::Discard),
+            _ => Err(value.invalid("unknown output")),
+        }
+    }
+}
+
+/// Runs a command attached to a pty, streaming its output into the output pane.
+#[derive(Debug, Clone)]
+pub struct PtyCommandAction {
+    pub command: String,
+    pub args: Vec<ExternalCommandArg>,
+    pub env: CommandEnv,
+}
+
+impl<'text, 'raw> TryFrom<nojson::RawJsonValue<'text, 'raw>> for PtyCommandAction {
+    type Error = nojson::JsonParseError;
+
+    fn try_from(value: nojson::RawJsonValue<'text, 'raw>) -> Result<Self, Self::Error> {
+        Ok(Self {
+            command: value.to_member("command")?.required()?.try_into()?,
+            args: value
+                .to_member("args")?
+                .map(Vec::try_from)?
+                .unwrap_or_default(),
+            env: CommandEnv::try_from(value)?,
+        })
+    }
+}
+
+/// Scans a file, or the output pane if `file` is omitted, for error locations.
+#[derive(Debug, Clone)]
+pub struct ErrorListAction {
+    pub file: Option<PathBuf>,
+    pub patterns: Vec<ErrorPattern>,
+    pub cwd: Option<CommandCwd>, // Directory that `file` and the paths in it are relative to
+}
+
+impl<'text, 'raw> TryFrom<nojson::RawJsonValue<'text, 'raw>> for ErrorListAction {
+    type Error = nojson::JsonParseError;
+
+    fn try_from(value: nojson::RawJsonValue<'text, 'raw>) -> Result<Self, Self::Error> {
+        Ok(Self {
+            file: value.to_member("file")?.map(PathBuf::try_from)?,
+            patterns: value
+                .to_member("patterns")?
+                .map(Vec::try_from)?
+                .unwrap_or_else(ErrorPattern::defaults),
+            cwd: value.to_member("cwd")?.map(CommandCwd::try_from)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum ErrorPattern {
+    Rustc, // `--> PATH:LINE:COL`
+    Gcc,   // `PATH:LINE:COL: MESSAGE`
+    Regex {
+        regex: regex::Regex,
+        path: usize, // Capture group indices (1, 2 and 3 by default)
+        line: usize,
+        column: usize, // The column is 1 if this group is missing or unmatched
+    },
+}
+
+impl ErrorPattern {
+    /// Patterns used if "patterns" is omitted, and for the output of pty commands.
+    pub fn defaults() -> Vec<Self> {
+        vec![Self::Rustc, Self::Gcc]
+    }
+}
+
+impl<'text, 'raw> TryFrom<nojson::RawJsonValue<'text, 'raw>> for ErrorPattern {
+    type Error = nojson::JsonParseError;
+
+    fn try_from(value: nojson::RawJsonValue<'text, 'raw>) -> Result<Self, Self::Error> {
+        if value.kind().is_string() {
+            return match value.to_unquoted_string_str()?.as_ref() {
+                "rustc" => Ok(Self::Rustc),
+                "gcc" => Ok(Self::Gcc),
+                _ => Err(value.invalid("unknown error pattern")),
+            };
+        }
+
+        let pattern = value.to_member("regex")?.required()?;
+        let regex = regex::Regex::new(&pattern.to_unquoted_string_str()?)
+            .map_err(|e| pattern.invalid(e))?;
+        Ok(Self::Regex {
+            regex,
+            path: value.to_member("path")?.map(usize::try_from)?.unwrap_or(1),
+            line: value.to_member("line")?.map(usize::try_from)?.unwrap_or(2),
+            column: value
+                .to_member("column")?
+                .map(usize::try_from)?
+                .unwrap_or(3),
         })
     }
 }
@@ -158,14 +609,64 @@ pub struct GrepAction {
     pub command: String,
     pub args: Vec<String>,
     pub forward: bool,
+    pub engine: GrepEngine,
+    pub scope: GrepScope,
+    pub history: GrepHistory,
+    pub env: CommandEnv,
 }
 
 impl<'text, 'raw> TryFrom<nojson::RawJsonValue<'text, 'raw>> for GrepAction {
     type Error = nojson::JsonParseError;
 
     fn try_from(value: nojson::RawJsonValue<'text, 'raw>) -> Result<Self, Self::Error> {
+        let ignore_case = value
+            .to_member("ignore_case")?
+            .map(bool::try_from)?
+            .unwrap_or(false);
+        let engine = match value.to_member("engine")?.map(Ok)? {
+            Some(engine) if engine.to_unquoted_string_str()? == "builtin" => GrepEngine::Builtin {
+                mode: value
+                    .to_member("mode")?
+                    .map(BuiltinGrepMode::try_from)?
+                    .unwrap_or(BuiltinGrepMode::Literal),
+                ignore_case,
+            },
+            Some(engine) if engine.to_unquoted_string_str()? != "external" => {
+                return Err(engine.invalid("unknown grep engine"));
+            }
+            _ => GrepEngine::External {
+                syntax: value
+                    .to_member("syntax")?
+                    .map(ExternalGrepSyntax::try_from)?
+                    .unwrap_or(ExternalGrepSyntax::Basic),
+                ignore_case,
+            },
+        };
+        let scope = match value.to_member("scope")?.map(Ok)? {
+            None => GrepScope::Buffer,
+            Some(scope) => match scope.to_unquoted_string_str()?.as_ref() {
+                "buffer" => GrepScope::Buffer,
+                "mark" => GrepScope::Mark,
+                "project" => GrepScope::Project {
+                    dir: value
+                        .to_member("dir")?
+                        .map(PathBuf::try_from)?
+                        .unwrap_or_else(|| PathBuf::from(".")),
+                },
+                _ => return Err(scope.invalid("unknown grep scope")),
+            },
+        };
+        if matches!(scope, GrepScope::Project { .. })
+            && matches!(engine, GrepEngine::Builtin { .. })
+        {
+            return Err(value.invalid("project scope requires an external grep command"));
+        }
+        let command = match engine {
+            GrepEngine::External { .. } => value.to_member("command")?.required()?.try_into()?,
+            GrepEngine::Builtin { .. } => String::new(),
+        };
         Ok(Self {
-            command: value.to_member("command")?.required()?.try_into()?,
+            command,
             args: value
                 .to_member("args")?
                 .map(Vec::try_from)?
@@ -174,6 +675,165 @@ impl<'text, 'raw> TryFrom<nojson::RawJsonValue<'text, 'raw>> for GrepAction {
                 .to_member("forward")?
                 .map(bool::try_from)?
                 .unwrap_or(true),
+            engine,
+            scope,
+            history: value
+                .to_member("history")?
+                .map(GrepHistory::try_from)?
+                .unwrap_or(GrepHistory::Shared),
+            env: CommandEnv::try_from(value)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum GrepHistory {
+    Shared,
+    Command, // Separate history per grep command
+    Context, // Separate history per binding context the grep was started from
+}
+
+impl<'text, 'raw> TryFrom<nojson::RawJsonValue<'text, 'raw>> for GrepHistory {
+    type Error = nojson::JsonParseError;
+
+    fn try_from(value: nojson::RawJsonValue<'text, 'raw>) -> Result<Self, Self::Error> {
+        match value.to_unquoted_string_str()?.as_ref() {
+            "shared" => Ok(Self::Shared),
+            "command" => Ok(Self::Command),
+            "context" => Ok(Self::Context),
+            _ => Err(value.invalid("unknown grep history")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum GrepEngine {
+    // `syntax` and `ignore_case` tell how the command interprets the query,
+    // which must agree with the options passed to it in "args"
+    External {
+        syntax: ExternalGrepSyntax,
+        ignore_case: bool,
+    },
+    Builtin {
+        mode: BuiltinGrepMode,
+        ignore_case: bool,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub enum GrepScope {
+    Buffer,
+    Mark,
+    Project { dir: PathBuf },
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum BuiltinGrepMode {
+    Literal,
+    Regex,
+}
+
+impl<'text, 'raw> TryFrom<nojson::RawJsonValue<'text, 'raw>> for BuiltinGrepMode {
+    type Error = nojson::JsonParseError;
+
+    fn try_from(value: nojson::RawJsonValue<'text, 'raw>) -> Result<Self, Self::Error> {
+        match value.to_unquoted_string_str()?.as_ref() {
+            "literal" => Ok(Self::Literal),
+            "regex" => Ok(Self::Regex),
+            _ => Err(value.invalid("unknown grep mode")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum ExternalGrepSyntax {
+    Fixed,    // `grep -F`
+    Basic,    // `grep -G` (default)
+    Extended, // `grep -E`
+    Perl,     // `grep -P`
+}
+
+impl<'text, 'raw> TryFrom<nojson::RawJsonValue<'text, 'raw>> for ExternalGrepSyntax {
+    type Error = nojson::JsonParseError;
+
+    fn try_from(value: nojson::RawJsonValue<'text, 'raw>) -> Result<Self, Self::Error> {
+        match value.to_unquoted_string_str()?.as_ref() {
+            "fixed" => Ok(Self::Fixed),
+            "basic" => Ok(Self::Basic),
+            "extended" => Ok(Self::Extended),
+            "perl" => Ok(Self::Perl),
+            _ => Err(value.invalid("unknown grep syntax")),
+        }
+    }
+}
+
+/// Reads a line of input, and then performs `then` with the input as the `PROMPT` variable.
+#[derive(Debug, Clone)]
+pub struct PromptAction {
+    pub label: String,
+    pub then: Box<Action>,
+    pub history: Option<String>, // History name (defaults to the label)
+    pub context: Option<String>, // Context to switch to after submission
+}
+
+impl<'text, 'raw> TryFrom<nojson::RawJsonValue<'text, 'raw>> for PromptAction {
+    type Error = nojson::JsonParseError;
+
+    fn try_from(value: nojson::RawJsonValue<'text, 'raw>) -> Result<Self, Self::Error> {
+        Ok(Self {
+            label: value.to_member("label")?.required()?.try_into()?,
+            then: Box::new(value.to_member("then")?.required()?.try_into()?),
+            history: value.to_member("history")?.map(String::try_from)?,
+            context: value.to_member("context")?.map(String::try_from)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BookmarkAction {
+    pub name: Option<String>, // Taken from the prompt input if omitted
+}
+
+impl<'text, 'raw> TryFrom<nojson::RawJsonValue<'text, 'raw>> for BookmarkAction {
+    type Error = nojson::JsonParseError;
+
+    fn try_from(value: nojson::RawJsonValue<'text, 'raw>) -> Result<Self, Self::Error> {
+        let name = value.to_member("name")?.map(String::try_from)?;
+        if let Some(name) = &name
+            && !Bookmarks::is_valid_name(name)
+        {
+            return Err(value.invalid("bookmark name must be non-empty and contain no whitespace"));
+        }
+        Ok(Self { name })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct GotoAction {
+    pub anchor: String, // Same format as the "goto-line" input, e.g., "src/main.rs:10:5"
+}
+
+impl<'text, 'raw> TryFrom<nojson::RawJsonValue<'text, 'raw>> for GotoAction {
+    type Error = nojson::JsonParseError;
+
+    fn try_from(value: nojson::RawJsonValue<'text, 'raw>) -> Result<Self, Self::Error> {
+        Ok(Self {
+            anchor: value.to_member("anchor")?.required()?.try_into()?,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TextInsertAction {
+    pub text: String,
+}
+
+impl<'text, 'raw> TryFrom<nojson::RawJsonValue<'text, 'raw>> for TextInsertAction {
+    type Error = nojson::JsonParseError;
+
+    fn try_from(value: nojson::RawJsonValue<'text, 'raw>) -> Result<Self, Self::Error> {
+        Ok(Self {
+            text: value.to_member("text")?.required()?.try_into()?,
         })
     }
 }
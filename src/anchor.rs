@@ -1,72 +1,396 @@
 use std::{
-    io::Write,
+    io::{BufRead, BufReader, Read, Seek, SeekFrom, Write},
     num::NonZeroUsize,
     path::{Path, PathBuf},
 };
 
 use orfail::OrFail;
 
+use crate::buffer::{TextBuffer, TextEdit, TextPosition};
+
+/// Maximum number of entries kept in the anchor log file.
+///
+/// The log is appended to and only compacted once it has grown to twice this size.
+const MAX_ANCHORS: usize = 1000;
+
 #[derive(Debug)]
 pub struct CursorAnchorLog {
     log_file_path: PathBuf,
+    jump_anchors: Vec<CursorAnchor>, // Log snapshot taken when jumping starts, most recent first
+    jump_index: Option<usize>,       // Position in `jump_anchors` while jumping
+    live_path: PathBuf,              // File that `live` anchors belong to
+    live: Vec<LiveAnchor>,
+    log_lines: Option<usize>, // Lines in the log file, counted on the first push
+}
+
+/// An anchor recorded in this session, tracking its position in the edited buffer.
+#[derive(Debug)]
+struct LiveAnchor {
+    logged: CursorAnchor, // As written in the log file
+    position: TextPosition,
 }
 
 impl CursorAnchorLog {
-    pub fn append(&self, anchor: CursorAnchor) -> orfail::Result<()> {
+    /// Appends `anchor`, which is at `position` in the current buffer.
+    pub fn append(&mut self, anchor: CursorAnchor, position: TextPosition) -> orfail::Result<()> {
+        self.jump_index = None;
+        self.jump_anchors.clear();
+        self.track(&anchor, position);
+        self.push(anchor).or_fail()
+    }
+
+    /// Shifts the in-session anchors of the file at `path` by `edits` made to its buffer.
+    pub fn shift(&mut self, path: &Path, edits: &[TextEdit]) {
+        if edits.is_empty() {
+            return;
+        }
+        if !is_same_path(path, &self.live_path) {
+            self.live.clear();
+            return;
+        }
+        for anchor in &mut self.live {
+            for edit in edits {
+                anchor.position = edit.shift_position(anchor.position);
+            }
+        }
+    }
+
+    /// Rewrites the anchors of the just saved `buffer` in the log with their current positions.
+    pub fn sync(&mut self, path: &Path, buffer: &TextBuffer) -> orfail::Result<()> {
+        if self.live.is_empty() || !is_same_path(path, &self.live_path) {
+            return Ok(());
+        }
+        let mut anchors = self.log_anchors().or_fail()?;
+        for anchor in &mut anchors {
+            if let Some(live) = self.find_live(anchor) {
+                *anchor = self.live_anchor(live, buffer);
+            }
+        }
+        for i in 0..self.live.len() {
+            let anchor = self.live_anchor(&self.live[i], buffer);
+            self.live[i].logged = anchor;
+        }
+        self.write(&anchors).or_fail()
+    }
+
+    fn track(&mut self, anchor: &CursorAnchor, position: TextPosition) {
+        let anchor = anchor.to_absolute();
+        if anchor.path != self.live_path {
+            self.live_path = anchor.path.clone();
+            self.live.clear();
+        }
+        self.live.push(LiveAnchor {
+            logged: anchor,
+            position,
+        });
+    }
+
+    fn find_live(&self, anchor: &CursorAnchor) -> Option<&LiveAnchor> {
+        self.live.iter().rev().find(|live| live.logged == *anchor)
+    }
+
+    fn live_anchor(&self, live: &LiveAnchor, buffer: &TextBuffer) -> CursorAnchor {
+        let row = live.position.row.min(buffer.rows());
+        let char = buffer
+            .char_index_at_col(row, live.position.col)
+            .unwrap_or_default();
+        CursorAnchor {
+            path: self.live_path.clone(),
+            line: NonZeroUsize::MIN.saturating_add(row),
+            char: NonZeroUsize::MIN.saturating_add(char),
+        }
+    }
+
+    /// Returns anchors from the most recent one.
+    pub fn recent_anchors(&self) -> orfail::Result<impl Iterator<Item = CursorAnchor>> {
+        let lines = if self.log_file_path.exists() {
+            Some(ReverseLines::open(&self.log_file_path).or_fail()?)
+        } else {
+            None
+        };
+        Ok(lines
+            .into_iter()
+            .flatten()
+            .map_while(|line| line.ok())
+            .filter_map(|line| line.trim().parse::<CursorAnchor>().ok()))
+    }
+
+    /// Returns the anchor before the one jumped to last.
+    ///
+    /// `current` is the cursor anchor at `position` in `buffer`, the buffer being edited.
+    pub fn prev_anchor(
+        &mut self,
+        current: &CursorAnchor,
+        position: TextPosition,
+        buffer: &TextBuffer,
+    ) -> orfail::Result<Option<CursorAnchor>> {
+        let current = &current.to_absolute();
+        if self.jump_index.is_none() {
+            // Record the starting position so that `next_anchor()` can come back to it
+            self.track(current, position);
+            self.push(current.clone()).or_fail()?;
+
+            // Other kk instances may append to the log while jumping, so a snapshot is used
+            let anchors = self.recent_anchors().or_fail()?.take(MAX_ANCHORS).collect();
+            self.jump_anchors = anchors;
+        }
+
+        let start = self.jump_index.map_or(0, |i| i + 1);
+        let found = self
+            .jump_anchors
+            .iter()
+            .enumerate()
+            .skip(start)
+            .map(|(i, a)| (i, self.resolve_in(a, current, buffer)))
+            .find(|(_, a)| a != current);
+        let Some((i, anchor)) = found else {
+            return Ok(None);
+        };
+        self.jump_index = Some(i);
+        Ok(Some(anchor))
+    }
+
+    /// Returns the anchor after the one jumped to last, going back towards where the jumps started.
+    pub fn next_anchor(
+        &mut self,
+        current: &CursorAnchor,
+        buffer: &TextBuffer,
+    ) -> orfail::Result<Option<CursorAnchor>> {
+        let current = &current.to_absolute();
+        let Some(end) = self.jump_index else {
+            return Ok(None);
+        };
+        let found = self
+            .jump_anchors
+            .iter()
+            .take(end)
+            .enumerate()
+            .rev()
+            .map(|(i, a)| (i, self.resolve_in(a, current, buffer)))
+            .find(|(_, a)| a != current);
+        let Some((i, anchor)) = found else {
+            return Ok(None);
+        };
+        self.jump_index = (i > 0).then_some(i);
+        Ok(Some(anchor))
+    }
+
+    // Maps a logged anchor to its current position if it was recorded in this session.
+    // Live positions only apply while `buffer` is the buffer of the live anchors' file.
+    fn resolve_in(
+        &self,
+        anchor: &CursorAnchor,
+        current: &CursorAnchor,
+        buffer: &TextBuffer,
+    ) -> CursorAnchor {
+        if current.path != self.live_path {
+            return anchor.clone();
+        }
+        match self.find_live(anchor) {
+            Some(live) => self.live_anchor(live, buffer),
+            None => anchor.clone(),
+        }
+    }
+
+    // Returns the logged anchors from the oldest one
+    fn log_anchors(&self) -> orfail::Result<Vec<CursorAnchor>> {
+        let mut anchors = self
+            .recent_anchors()
+            .or_fail()?
+            .take(MAX_ANCHORS)
+            .collect::<Vec<_>>();
+        anchors.reverse();
+        Ok(anchors)
+    }
+
+    // Appends `anchor` unless it is the same as the last one,
+    // compacting the log to `MAX_ANCHORS` entries once it has grown to twice that size
+    //
+    // Lines appended by other kk instances are not counted, which only delays the compaction.
+    fn push(&mut self, anchor: CursorAnchor) -> orfail::Result<()> {
+        let anchor = anchor.to_absolute();
+        if self.recent_anchors().or_fail()?.next().as_ref() == Some(&anchor) {
+            return Ok(());
+        }
+        let log_lines = match self.log_lines {
+            Some(n) => n,
+            None if self.log_file_path.exists() => {
+                let file = std::fs::File::open(&self.log_file_path).or_fail()?;
+                BufReader::new(file).lines().count()
+            }
+            None => 0,
+        };
         let mut file = std::fs::OpenOptions::new()
             .create(true)
             .append(true)
             .open(&self.log_file_path)
             .or_fail()?;
-        writeln!(file, "{}", anchor).or_fail()?;
+        writeln!(file, "{anchor}").or_fail()?;
+        self.log_lines = Some(log_lines + 1);
+
+        if log_lines + 1 > 2 * MAX_ANCHORS {
+            let mut anchors = self.log_anchors().or_fail()?;
+            anchors.dedup();
+            self.write(&anchors).or_fail()?;
+        }
         Ok(())
     }
 
-    pub fn recent_anchors(&self) -> orfail::Result<impl Iterator<Item = CursorAnchor>> {
-        // TODO: optimize
-        let log = std::fs::read_to_string(&self.log_file_path).or_fail()?;
-        Ok(log
-            .lines()
-            .rev()
-            .filter_map(|line| line.trim().parse::<CursorAnchor>().ok())
-            .collect::<Vec<_>>()
-            .into_iter())
+    fn write(&mut self, anchors: &[CursorAnchor]) -> orfail::Result<()> {
+        let excess = anchors.len().saturating_sub(MAX_ANCHORS);
+
+        // Write to a temporary file first so that the log is never left half-written.
+        // The file name is unique to this process, as other kk instances may compact at the same time.
+        let mut tmp_path = self.log_file_path.clone().into_os_string();
+        tmp_path.push(format!(".{}.tmp", std::process::id()));
+        let mut file = std::fs::File::create(&tmp_path).or_fail()?;
+        for anchor in &anchors[excess..] {
+            writeln!(file, "{}", anchor).or_fail()?;
+        }
+        std::fs::rename(&tmp_path, &self.log_file_path).or_fail()?;
+        self.log_lines = Some(anchors.len() - excess);
+        Ok(())
     }
+}
 
-    pub fn prev_anchor(&self, current: &CursorAnchor) -> orfail::Result<Option<CursorAnchor>> {
-        let n = 1000; // TODO
-        if let Some(a) = self
-            .recent_anchors()
-            .or_fail()?
-            .take(n)
-            .skip_while(|a| a != current)
-            .nth(1)
-        {
-            return Ok(Some(a));
+impl Default for CursorAnchorLog {
+    fn default() -> Self {
+        let dir = std::env::var_os("HOME") // TODO
+            .map(PathBuf::from)
+            .unwrap_or_default();
+        Self {
+            log_file_path: dir.join(".kk.anchors"),
+            jump_anchors: Vec::new(),
+            jump_index: None,
+            live_path: PathBuf::new(),
+            live: Vec::new(),
+            log_lines: None,
         }
+    }
+}
+
+/// Reads the lines of a file from the end, a chunk at a time.
+#[derive(Debug)]
+struct ReverseLines {
+    file: std::fs::File,
+    pos: u64,         // Start offset of the bytes already read
+    pending: Vec<u8>, // Bytes read but not yet returned as lines
+}
+
+impl ReverseLines {
+    const CHUNK_SIZE: u64 = 8192;
+
+    fn open(path: &Path) -> orfail::Result<Self> {
+        let file = std::fs::File::open(path).or_fail()?;
+        let pos = file.metadata().or_fail()?.len();
+        Ok(Self {
+            file,
+            pos,
+            pending: Vec::new(),
+        })
+    }
 
+    fn read_chunk(&mut self) -> std::io::Result<()> {
+        let n = self.pos.min(Self::CHUNK_SIZE);
+        self.pos -= n;
+        self.file.seek(SeekFrom::Start(self.pos))?;
+        let mut chunk = vec![0; n as usize];
+        self.file.read_exact(&mut chunk)?;
+        chunk.append(&mut self.pending);
+        self.pending = chunk;
+        Ok(())
+    }
+}
+
+impl Iterator for ReverseLines {
+    type Item = std::io::Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(i) = self.pending.iter().rposition(|&b| b == b'\n') {
+                let line = self.pending.split_off(i + 1);
+                self.pending.truncate(i);
+                if line.is_empty() {
+                    continue;
+                }
+                return Some(Ok(String::from_utf8_lossy(&line).into_owned()));
+            }
+            if self.pos == 0 {
+                if self.pending.is_empty() {
+                    return None;
+                }
+                let line = std::mem::take(&mut self.pending);
+                return Some(Ok(String::from_utf8_lossy(&line).into_owned()));
+            }
+            if let Err(e) = self.read_chunk() {
+                return Some(Err(e));
+            }
+        }
+    }
+}
+
+/// Named cursor anchors, stored as `NAME ANCHOR` lines in `~/.kk.bookmarks`.
+#[derive(Debug)]
+pub struct Bookmarks {
+    pub file_path: PathBuf,
+}
+
+impl Bookmarks {
+    pub fn list(&self) -> orfail::Result<Vec<(String, CursorAnchor)>> {
+        if !self.file_path.exists() {
+            return Ok(Vec::new());
+        }
+        let text = std::fs::read_to_string(&self.file_path).or_fail()?;
+        Ok(text
+            .lines()
+            .filter_map(|line| {
+                let (name, anchor) = line.split_once(' ')?;
+                Some((name.to_owned(), anchor.trim().parse().ok()?))
+            })
+            .collect())
+    }
+
+    pub fn get(&self, name: &str) -> orfail::Result<Option<CursorAnchor>> {
         Ok(self
-            .recent_anchors()
+            .list()
             .or_fail()?
-            .take(n)
-            .find(|a| a.path == current.path))
+            .into_iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, anchor)| anchor))
+    }
+
+    pub fn set(&self, name: &str, anchor: CursorAnchor) -> orfail::Result<()> {
+        let mut bookmarks = self.list().or_fail()?;
+        bookmarks.retain(|(n, _)| n != name);
+        bookmarks.push((name.to_owned(), anchor.to_absolute()));
+        bookmarks.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut text = String::new();
+        for (name, anchor) in bookmarks {
+            text.push_str(&format!("{name} {anchor}\n"));
+        }
+        std::fs::write(&self.file_path, text).or_fail()
+    }
+
+    pub fn is_valid_name(name: &str) -> bool {
+        !name.is_empty() && !name.contains(char::is_whitespace)
     }
 }
 
-impl Default for CursorAnchorLog {
+impl Default for Bookmarks {
     fn default() -> Self {
         let dir = std::env::var_os("HOME") // TODO
             .map(PathBuf::from)
             .unwrap_or_default();
         Self {
-            log_file_path: dir.join(".kk.anchors"),
+            file_path: dir.join(".kk.bookmarks"),
         }
     }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct CursorAnchor {
-    pub path: PathBuf, // TODO: make the path absolute
+    pub path: PathBuf, // Absolute when stored in the anchor log or the bookmarks
     pub line: NonZeroUsize,
     pub char: NonZeroUsize,
 }
@@ -87,20 +411,83 @@ impl CursorAnchor {
             });
         }
 
-        let mut tokens = s.split(':');
-        let path: PathBuf = tokens.next()?.parse().ok()?;
-        let line: NonZeroUsize = tokens.next()?.parse().ok()?;
-        let maybe_char_str = tokens.next();
+        // As the path may contain ':', every "<FILE>:<LINE>" split is a candidate.
+        // Prefers the first one naming an existing file.
+        let candidates = s
+            .match_indices(':')
+            .filter_map(|(i, _)| {
+                let path = &s[..i];
+                let mut tokens = s[i + 1..].splitn(3, ':');
+                let line: NonZeroUsize = tokens.next()?.parse().ok()?;
+                let maybe_char_str = tokens.next();
 
-        let char = if let Some(char) = maybe_char_str.and_then(|s| s.parse::<NonZeroUsize>().ok()) {
-            // FORMAT: <FILE>:<LINE>:<CHAR>
-            char
-        } else {
-            // FORMAT: <FILE>:<LINE>
-            NonZeroUsize::MIN
-        };
-        Some(Self { path, line, char })
+                let char = if let Some(char) =
+                    maybe_char_str.and_then(|s| s.parse::<NonZeroUsize>().ok())
+                {
+                    // FORMAT: <FILE>:<LINE>:<CHAR>
+                    char
+                } else {
+                    // FORMAT: <FILE>:<LINE>
+                    NonZeroUsize::MIN
+                };
+                (!path.is_empty()).then(|| Self {
+                    path: PathBuf::from(path),
+                    line,
+                    char,
+                })
+            })
+            .collect::<Vec<_>>();
+        let i = candidates.iter().position(|a| a.path.exists()).unwrap_or(0);
+        candidates.into_iter().nth(i)
+    }
+
+    /// Returns this anchor with the path made absolute.
+    pub fn to_absolute(&self) -> Self {
+        Self {
+            path: absolute_path(&self.path),
+            ..self.clone()
+        }
+    }
+
+    /// Returns this anchor with a relative path taken as relative to `dir`.
+    pub fn in_dir(&self, dir: &Path) -> Self {
+        Self {
+            path: relative_path(&dir.join(&self.path)),
+            ..self.clone()
+        }
     }
+
+    /// Returns this anchor with the path made relative to the current directory, for display.
+    pub fn to_relative(&self) -> Self {
+        Self {
+            path: relative_path(&self.path),
+            ..self.clone()
+        }
+    }
+}
+
+/// Makes `path` absolute, resolving symbolic links if the file exists.
+pub fn absolute_path(path: &Path) -> PathBuf {
+    std::fs::canonicalize(path)
+        .or_else(|_| std::path::absolute(path))
+        .unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// Returns `path` relative to the current directory if it is under the directory.
+pub fn relative_path(path: &Path) -> PathBuf {
+    let Ok(dir) = std::env::current_dir() else {
+        return path.to_path_buf();
+    };
+    let path = absolute_path(path);
+    let dir = absolute_path(&dir);
+    path.strip_prefix(&dir)
+        .map(|p| p.to_path_buf())
+        .unwrap_or(path)
+}
+
+/// Returns whether `a` and `b` refer to the same file.
+pub fn is_same_path(a: &Path, b: &Path) -> bool {
+    a == b || absolute_path(a) == absolute_path(b)
 }
 
 impl std::fmt::Display for CursorAnchor {
@@ -113,9 +500,10 @@ impl std::str::FromStr for CursorAnchor {
     type Err = String;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let mut parts = s.splitn(3, ':');
+        // Splitting from the end, as the path may contain ':'
+        let mut parts = s.rsplitn(3, ':');
 
-        let Some(((path_str, line_str), char_str)) =
+        let Some(((char_str, line_str), path_str)) =
             parts.next().zip(parts.next()).zip(parts.next())
         else {
             return Err(format!("expected \"PATH:LINE:CHAR\", but got {s:?}"));
@@ -1,14 +1,18 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use mame::terminal::UnicodeTerminalFrame as TerminalFrame;
 use orfail::OrFail;
-use tuinix::{Terminal, TerminalEvent, TerminalInput, TerminalRegion};
+use tuinix::{KeyCode, KeyInput, Terminal, TerminalEvent, TerminalInput, TerminalRegion};
 
 use crate::{
-    action::Action,
+    action::{Action, GrepScope},
     anchor::CursorAnchorLog,
+    command::{resolve_cwd, run_mame_command},
     grep_mode::{GrepMode, GrepQueryRenderer, Highlight},
     message_line::MessageLineRenderer,
+    preview::Preview,
+    prompt_mode::{PromptMode, PromptRenderer},
+    remote::{RemoteRequest, RemoteServer},
     state::State,
     status_line::StatusLineRenderer,
     text_area::TextAreaRenderer,
@@ -24,12 +28,15 @@ pub struct App {
     text_area: TextAreaRenderer,
     message_line: MessageLineRenderer,
     status_line: StatusLineRenderer,
-    file_preview: Option<mame::preview::FilePreview>,
+    prompt_context: Option<mame::action::BindingContextName>, // Context to return to from "@prompt"
+    remote: Option<RemoteServer>,
     exit: bool,
 }
 
 impl App {
-    pub fn new(path: PathBuf) -> orfail::Result<Self> {
+    pub fn new(path: PathBuf, socket: Option<&Path>) -> orfail::Result<Self> {
+        // Bound before entering the terminal so that errors are shown normally
+        let remote = socket.map(RemoteServer::bind).transpose().or_fail()?;
         let terminal = Terminal::new().or_fail()?;
         let config = mame::action::BindingConfig::load_from_str(
             "<DEFAULT>",
@@ -45,7 +52,8 @@ impl App {
             text_area: TextAreaRenderer,
             message_line: MessageLineRenderer,
             status_line: StatusLineRenderer,
-            file_preview: None,
+            prompt_context: None,
+            remote,
             exit: false,
         })
     }
@@ -56,7 +64,24 @@ impl App {
         while !self.exit {
             self.render().or_fail()?;
 
-            match self.terminal.poll_event(&[], &[], None).or_fail()? {
+            // Wait for user input, as well as for the output of running grep and pty commands
+            // and for actions sent through the remote socket
+            let mut fds = self
+                .state
+                .grep_mode
+                .as_ref()
+                .map(|grep| grep.pending_fds())
+                .unwrap_or_default();
+            fds.extend(self.state.output.as_ref().and_then(|o| o.pending_fd()));
+            fds.extend(self.remote.iter().flat_map(|r| r.fds()));
+            let write_fds = self
+                .state
+                .grep_mode
+                .as_ref()
+                .and_then(|grep| grep.pending_write_fd())
+                .into_iter()
+                .collect::<Vec<_>>();
+            match self.terminal.poll_event(&fds, &write_fds, None).or_fail()? {
                 Some(TerminalEvent::Input(input)) => {
                     self.handle_input(input).or_fail()?;
 
@@ -71,8 +96,18 @@ impl App {
                     }
                 }
                 Some(TerminalEvent::Resize(_size)) => {}
-                Some(TerminalEvent::FdReady { .. }) => {
-                    unreachable!()
+                Some(TerminalEvent::FdReady { fd, .. }) => {
+                    if self.state.output.as_ref().and_then(|o| o.pending_fd()) == Some(fd) {
+                        self.state.handle_output_fd_ready(fd);
+                    } else if let Some(remote) = &mut self.remote
+                        && remote.fds().contains(&fd)
+                    {
+                        for request in remote.handle_fd_ready(fd).or_fail()? {
+                            self.handle_remote_request(request).or_fail()?;
+                        }
+                    } else {
+                        self.state.handle_grep_fd_ready(fd);
+                    }
                 }
                 None => {}
             }
@@ -102,10 +137,71 @@ impl App {
             self.context = context;
         }
 
+        // Leave the confirm context once there are no more hits to confirm
+        if self.context.get() == "@grep-confirm"
+            && self
+                .state
+                .grep_mode
+                .as_ref()
+                .is_none_or(|g| g.confirm_replaced.is_none())
+        {
+            self.context = mame::action::BindingContextName::new("@grep");
+        }
+
+        // Leave the grep context once the grep mode has ended without switching contexts
+        if self.context.get() == "@grep" && self.state.grep_mode.is_none() {
+            self.context = mame::action::BindingContextName::new("@main");
+        }
+
+        // Return from the prompt context once the prompt is submitted or canceled
+        if self.context.get() == "@prompt"
+            && self.state.prompt.is_none()
+            && let Some(context) = self.prompt_context.take()
+        {
+            self.context = context;
+        }
+
+        Ok(())
+    }
+
+    fn handle_remote_request(&mut self, request: RemoteRequest) -> orfail::Result<()> {
+        let action = match request.parse_action() {
+            Ok(action) => action,
+            Err(e) => {
+                self.state
+                    .set_message(format!("Invalid remote action: {e}"));
+                request.reply(Err(e));
+                return Ok(());
+            }
+        };
+
+        // There is no key behind a remote action, so "char-insert" inserts nothing
+        let input = TerminalInput::Key(KeyInput {
+            ctrl: false,
+            alt: false,
+            code: KeyCode::Escape,
+        });
+        self.state.failure = None;
+        if let Err(e) = self.handle_action(action, input) {
+            // Unlike a key, a remote client should not be able to make kk exit
+            self.state.set_message(e.message.clone());
+            request.reply(Err(e.message));
+            return Ok(());
+        }
+        request.reply(self.state.failure.take().map_or(Ok(()), Err));
         Ok(())
     }
 
     fn handle_action(&mut self, action: Action, input: TerminalInput) -> orfail::Result<()> {
+        self.handle_action_inner(action, input).or_fail()?;
+
+        // Keep the mark and the anchors of this session at the same text across edits
+        let edits = self.state.take_edit_journal();
+        self.anchor_log.shift(&self.state.path, &edits);
+        Ok(())
+    }
+
+    fn handle_action_inner(&mut self, action: Action, input: TerminalInput) -> orfail::Result<()> {
         match action {
             Action::Multiple(actions) => {
                 for action in actions {
@@ -116,14 +212,37 @@ impl App {
                 self.exit = true;
             }
             Action::Cancel => {
-                self.state.mark = None;
+                self.state.clear_mark();
+                let mut searching = false;
                 if let Some(grep) = self.state.grep_mode.take() {
+                    // Dropping the grep mode also kills the running grep command
+                    searching = grep.is_searching();
                     grep.save_query().or_fail()?;
                 }
                 self.state.highlight = Highlight::default();
-                self.state.set_message("Canceled");
+                self.state.prompt = None;
+                if searching {
+                    self.state.set_message("Canceled search");
+                } else {
+                    self.state.set_message("Canceled");
+                }
+            }
+            Action::GrepCancelSearch => {
+                if let Some(grep) = &mut self.state.grep_mode
+                    && grep.cancel_search()
+                {
+                    self.state.set_message("Canceled search");
+                } else {
+                    // Nothing to cancel, so leave the grep mode as "cancel" does
+                    self.handle_action(Action::Cancel, input).or_fail()?;
+                }
+            }
+            Action::BufferSave => {
+                self.state.handle_buffer_save().or_fail()?;
+                self.anchor_log
+                    .sync(&self.state.path, &self.state.buffer)
+                    .or_fail()?;
             }
-            Action::BufferSave => self.state.handle_buffer_save().or_fail()?,
             Action::BufferReload => self.state.handle_buffer_reload().or_fail()?,
             Action::BufferUndo => self.state.handle_buffer_undo(),
             Action::CursorUp => self.state.handle_cursor_up(),
@@ -155,36 +274,111 @@ impl App {
             Action::CharDeleteBackward => self.state.handle_char_delete_backward(),
             Action::CharDeleteForward => self.state.handle_char_delete_forward(),
             Action::LineDelete => self.state.handle_line_delete().or_fail()?,
+            Action::QueryWordLeft => self.state.handle_query_word_left(),
+            Action::QueryWordRight => self.state.handle_query_word_right(),
+            Action::QueryWordDeleteBackward => self.state.handle_query_word_delete_backward(),
+            Action::QueryWordDeleteForward => self.state.handle_query_word_delete_forward(),
+            Action::QueryKill => self.state.handle_query_kill(),
+            Action::QueryTranspose => self.state.handle_query_transpose(),
             Action::MarkSet => self.state.handle_mark_set(),
             Action::MarkCopy => self.state.handle_mark_copy().or_fail()?,
             Action::MarkCut => self.state.handle_mark_cut().or_fail()?,
+            Action::RectMarkSet => self.state.handle_rect_mark_set(),
+            Action::RectCopy => self.state.handle_rect_copy().or_fail()?,
+            Action::RectCut => self.state.handle_rect_cut().or_fail()?,
+            Action::RectPaste => self.state.handle_rect_paste().or_fail()?,
+            Action::RectInsert => self.state.handle_rect_insert().or_fail()?,
             Action::ClipboardPaste => self.state.handle_clipboard_paste().or_fail()?,
-            Action::ShellCommand(action) => {
-                self.state.handle_external_command(&action).or_fail()?
+            Action::ShellCommand(action) | Action::BufferFilter(action) => {
+                self.state.set_message(format!(
+                    "Executing `$ {}` ... (C-g to interrupt)",
+                    action.command
+                ));
+                self.render().or_fail()?;
+
+                let context = self.context.get();
+                let terminal = &mut self.terminal;
+                self.state
+                    .handle_external_command(&action, context, |timeout| {
+                        poll_interrupt(terminal, timeout)
+                    })
+                    .or_fail()?;
             }
-            Action::Command(cmd) => {
+            Action::Command(action) => {
+                let context = self.context.get();
+                let mut cmd = action.command;
+                cmd.args = self.state.command_args(&action.args, context);
+                cmd.envs.extend(action.env.env);
+                for (name, value) in self.state.command_envs(context) {
+                    // Explicit "envs" and "env" take precedence
+                    cmd.envs.entry(name).or_insert(value);
+                }
+                let dir = action
+                    .env
+                    .cwd
+                    .map(|cwd| resolve_cwd(&cwd, &self.state.path));
                 let name = cmd.command_line();
-                self.state.set_message(format!("Executing `$ {name}` ..."));
+                self.state
+                    .set_message(format!("Executing `$ {name}` ... (C-g to interrupt)"));
                 self.render().or_fail()?;
 
-                let output = cmd.execute().or_fail()?;
-                if let Some(code) = output.status.code() {
-                    self.state
-                        .set_message(format!("[{code}] `$ {name}` exited"));
+                let terminal = &mut self.terminal;
+                let run = run_mame_command(&cmd, dir.as_deref(), action.timeout, |timeout| {
+                    poll_interrupt(terminal, timeout)
+                })
+                .or_fail()?;
+                let summary = run.summary(cmd.command_line());
+                if run.success() {
+                    self.state.set_message(summary);
                 } else {
-                    self.state.set_message(format!("`$ {name}` exited"));
+                    self.state.set_failure_message(summary);
                 }
             }
+            Action::PtyCommand(action) => {
+                let size = self.terminal.size();
+                self.state
+                    .handle_pty_command(&action, self.context.get(), size)
+                    .or_fail()?;
+            }
+            Action::OutputClose => self.state.handle_output_close(),
+            Action::ErrorList(action) => self.state.handle_error_list(&action).or_fail()?,
+            Action::ErrorNext => self.state.handle_error_next(),
+            Action::ErrorPrev => self.state.handle_error_prev(),
             Action::CursorAnchor => {
                 let anchor = self.state.current_cursor_anchor();
-                self.state.set_message(format!("Anchor: {anchor}"));
-                self.anchor_log.append(anchor).or_fail()?;
+                self.state
+                    .set_message(format!("Anchor: {}", anchor.to_relative()));
+                self.anchor_log
+                    .append(anchor, self.state.cursor_position())
+                    .or_fail()?;
             }
             Action::CursorJump => {
                 let current = self.state.current_cursor_anchor();
-                if let Some(anchor) = self.anchor_log.prev_anchor(&current).or_fail()? {
-                    self.state.restore_anchor(&anchor).or_fail()?;
-                    self.state.set_message(format!("Jump: {anchor}"));
+                let position = self.state.cursor_position();
+                let buffer = &self.state.buffer;
+                if let Some(anchor) = self
+                    .anchor_log
+                    .prev_anchor(&current, position, buffer)
+                    .or_fail()?
+                {
+                    if self.state.restore_anchor(&anchor).or_fail()? {
+                        self.state
+                            .set_message(format!("Jump: {}", anchor.to_relative()));
+                    }
+                } else {
+                    self.state.set_message("No older anchor");
+                }
+            }
+            Action::CursorJumpForward => {
+                let current = self.state.current_cursor_anchor();
+                let buffer = &self.state.buffer;
+                if let Some(anchor) = self.anchor_log.next_anchor(&current, buffer).or_fail()? {
+                    if self.state.restore_anchor(&anchor).or_fail()? {
+                        self.state
+                            .set_message(format!("Jump: {}", anchor.to_relative()));
+                    }
+                } else {
+                    self.state.set_message("No newer anchor");
                 }
             }
             Action::Echo(m) => {
@@ -192,69 +386,160 @@ impl App {
             }
             Action::Grep(action) => {
                 self.state.finish_editing();
-                self.state.grep_mode = Some(GrepMode::new(action));
-                self.state.mark = None;
-                self.state.set_message("Entered grep mode");
+                if self.state.rect_mark && self.state.mark.is_some() {
+                    // Hits and replacements are confined to a linear region
+                    self.state
+                        .set_failure_message("Grep does not support a rectangle mark");
+                    return Ok(());
+                }
+                let mark_region = self.state.mark_region();
+                let whole_buffer = matches!(action.scope, GrepScope::Mark) && mark_region.is_none();
+                self.state.grep_mode = Some(GrepMode::new(action, mark_region, self.context.get()));
+                self.state.clear_mark();
+                if whole_buffer {
+                    self.state
+                        .set_message("Entered grep mode (no mark, searching the whole buffer)");
+                } else {
+                    self.state.set_message("Entered grep mode");
+                }
             }
             Action::GrepNextHit => {
-                if !self.state.highlight.items.is_empty() {
+                if self.state.has_grep_hits() {
                     self.state.handle_grep_next_hit();
                 } else {
                     self.state.set_message("No grep hits available");
                 }
             }
             Action::GrepPrevHit => {
-                if !self.state.highlight.items.is_empty() {
+                if self.state.has_grep_hits() {
                     self.state.handle_grep_prev_hit();
                 } else {
                     self.state.set_message("No grep hits available");
                 }
             }
+            Action::GrepFirstHit => self.state.handle_grep_first_hit(),
+            Action::GrepLastHit => self.state.handle_grep_last_hit(),
             Action::GrepNextQuery => {
                 self.state.handle_grep_next_query();
             }
+            Action::GrepYankWord => self.state.handle_grep_yank_word(),
+            Action::GrepResultsOpen => self.state.handle_grep_results_open().or_fail()?,
             Action::GrepPrevQuery => {
                 self.state.handle_grep_prev_query();
             }
+            Action::Prompt(action) => {
+                if self.context.get() != "@prompt" {
+                    self.prompt_context = Some(self.context.clone());
+                }
+                self.state.prompt = Some(PromptMode::new(action));
+            }
+            Action::PromptSubmit => {
+                let Some(prompt) = self.state.prompt.take() else {
+                    self.state.set_message("No active prompt");
+                    return Ok(());
+                };
+                prompt.save_input().or_fail()?;
+                if let Some(context) = &prompt.action.context {
+                    self.prompt_context = Some(mame::action::BindingContextName::new(context));
+                }
+                self.state.prompt_value = Some(prompt.input.to_text());
+                let result = self.handle_action(*prompt.action.then, input);
+                self.state.prompt_value = None;
+                result.or_fail()?;
+            }
+            Action::PromptCancel => {
+                if self.state.prompt.take().is_some() {
+                    self.state.set_message("Canceled");
+                }
+            }
+            Action::PromptPrevInput => self.state.handle_prompt_prev_input(),
+            Action::PromptNextInput => self.state.handle_prompt_next_input(),
             Action::GotoLine => self.state.handle_goto_line().or_fail()?,
+            Action::GotoCursorLine => self.state.handle_goto_cursor_line().or_fail()?,
+            Action::Goto(action) => self.state.handle_goto(&action).or_fail()?,
+            Action::TextInsert(action) => self.state.handle_text_insert(&action),
+            Action::BookmarkSet(action) => self.state.handle_bookmark_set(&action).or_fail()?,
+            Action::BookmarkJump(action) => self.state.handle_bookmark_jump(&action).or_fail()?,
+            Action::BookmarkList => self.state.handle_bookmark_list().or_fail()?,
             Action::CursorLeftSkipChars(c) => self.state.handle_cursor_left_skip_chars(&c.chars),
             Action::CursorRightSkipChars(c) => self.state.handle_cursor_right_skip_chars(&c.chars),
             Action::GrepReplaceHit => self.state.handle_grep_replace_hit().or_fail()?,
-            Action::FilePreviewOpen(spec) => {
-                self.file_preview = Some(mame::preview::FilePreview::new(&spec).or_fail()?);
+            Action::GrepReplaceAll => self.state.handle_grep_replace_all().or_fail()?,
+            Action::GrepReplaceConfirm => self.state.handle_grep_replace_confirm(),
+            Action::GrepConfirmYes => self.state.handle_grep_confirm_yes().or_fail()?,
+            Action::GrepConfirmNo => self.state.handle_grep_confirm_no(),
+            Action::GrepConfirmAll => self.state.handle_grep_confirm_all().or_fail()?,
+            Action::GrepConfirmQuit => self.state.handle_grep_confirm_quit(),
+            Action::MultiCursorFromHits => self.state.handle_multi_cursor_from_hits(),
+            Action::MultiCursorClear => self.state.handle_multi_cursor_clear(),
+            Action::FilePreviewOpen(action) => {
+                let mut spec = action.spec;
+                if let Some(cwd) = &action.cwd {
+                    let dir = resolve_cwd(cwd, &self.state.path);
+                    for pane in [&mut spec.left_pane, &mut spec.right_pane]
+                        .into_iter()
+                        .flatten()
+                    {
+                        pane.file = dir.join(&pane.file);
+                    }
+                }
+                self.state.preview = Some(Preview::load(&spec).or_fail()?);
             }
             Action::FilePreviewClose => {
-                self.file_preview = None;
+                self.state.preview = None;
             }
+            Action::PreviewScrollUp => self.state.handle_preview_scroll_up(),
+            Action::PreviewScrollDown => self.state.handle_preview_scroll_down(),
+            Action::PreviewCopyLine => self.state.handle_preview_copy_line().or_fail()?,
         }
         Ok(())
     }
 
     fn text_area_region(&self) -> TerminalRegion {
-        let footer_rows = if self.state.grep_mode.is_some() { 3 } else { 2 };
+        let footer_rows = if self.state.grep_mode.is_some() || self.state.prompt.is_some() {
+            3
+        } else {
+            2
+        };
         self.terminal.size().to_region().drop_bottom(footer_rows)
     }
 
     fn render(&mut self) -> orfail::Result<()> {
         let mut frame = TerminalFrame::new(self.terminal.size());
 
-        let mut preview = self.file_preview.take();
+        let mut preview = self.state.preview.take();
         let region = self.text_area_region();
         self.state.adjust_viewport(region.size);
         self.render_region(&mut frame, region, |frame| {
             self.text_area.render(&self.state, frame).or_fail()?;
+            let max_rows = (region.size.rows / 3).saturating_sub(1);
             if let Some(preview) = &mut preview {
-                preview.render(frame).or_fail()?;
+                preview.text_preview(max_rows).render(frame).or_fail()?;
+            } else if let Some(grep) = &self.state.grep_mode
+                && let Some(mut hits_preview) = grep.project_hits_preview(max_rows)
+            {
+                hits_preview.render(frame).or_fail()?;
+            } else if let Some(output) = &self.state.output
+                && let errors = self.state.errors.as_ref().filter(|e| e.from_output)
+                && let Some(mut output_preview) = output.preview(max_rows, errors)
+            {
+                output_preview.render(frame).or_fail()?;
             }
             Ok(())
         })?;
-        self.file_preview = preview;
+        self.state.preview = preview;
 
         let mut frame_region = frame.size().to_region();
-        let mut grep_region = frame_region;
-        if self.state.grep_mode.is_some() {
-            grep_region = frame_region.take_bottom(1);
-            self.render_region(&mut frame, grep_region, |frame| {
+        let mut query_region = frame_region;
+        if self.state.prompt.is_some() {
+            query_region = frame_region.take_bottom(1);
+            self.render_region(&mut frame, query_region, |frame| {
+                PromptRenderer.render(&self.state, frame).or_fail()
+            })?;
+            frame_region = frame_region.drop_bottom(1);
+        } else if self.state.grep_mode.is_some() {
+            query_region = frame_region.take_bottom(1);
+            self.render_region(&mut frame, query_region, |frame| {
                 GrepQueryRenderer.render(&self.state, frame).or_fail()
             })?;
             frame_region = frame_region.drop_bottom(1);
@@ -281,9 +566,12 @@ impl App {
         );
         legend.render(&mut frame).or_fail()?;
 
-        if let Some(grep) = &self.state.grep_mode {
+        if let Some(prompt) = &self.state.prompt {
             self.terminal
-                .set_cursor(Some(grep.cursor_position(grep_region)));
+                .set_cursor(Some(prompt.cursor_position(query_region)));
+        } else if let Some(grep) = &self.state.grep_mode {
+            self.terminal
+                .set_cursor(Some(grep.cursor_position(query_region)));
         } else {
             self.terminal
                 .set_cursor(Some(self.state.terminal_cursor_position()));
@@ -309,3 +597,15 @@ impl App {
         Ok(())
     }
 }
+
+/// Waits up to `timeout` for `C-c` or `C-g`, which interrupt a running command.
+///
+/// Other inputs are discarded, as they were typed without seeing the command's result.
+fn poll_interrupt(terminal: &mut Terminal, timeout: std::time::Duration) -> orfail::Result<bool> {
+    let Some(TerminalEvent::Input(TerminalInput::Key(key))) =
+        terminal.poll_event(&[], &[], Some(timeout)).or_fail()?
+    else {
+        return Ok(false);
+    };
+    Ok(key.ctrl && matches!(key.code, KeyCode::Char('c' | 'g')))
+}
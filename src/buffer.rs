@@ -6,6 +6,7 @@ use orfail::OrFail;
 pub struct TextBuffer {
     pub text: Vec<TextLine>,
     pub dirty: bool,
+    pub journal: Vec<TextEdit>, // Edits made since the journal was last taken
 }
 
 impl TextBuffer {
@@ -17,9 +18,19 @@ impl TextBuffer {
             .map(|l| TextLine(l.chars().collect()))
             .collect();
         self.dirty = false;
+        self.journal.clear();
         Ok(())
     }
 
+    /// Records an edit made by directly modifying `text`.
+    pub fn record_edit(&mut self, edit: TextEdit) {
+        self.journal.push(edit);
+    }
+
+    pub fn take_journal(&mut self) -> Vec<TextEdit> {
+        std::mem::take(&mut self.journal)
+    }
+
     pub fn to_single_text(&self) -> String {
         self.text
             .iter()
@@ -47,11 +58,20 @@ impl TextBuffer {
     pub fn delete_char_at(&mut self, pos: TextPosition) -> bool {
         // Store the character for undo before deleting
         if let Some(line) = self.text.get(pos.row)
-            && let Some(_ch) = line.char_at_col(pos.col) {
-                self.delete_char_at_internal(pos);
-                self.dirty = true;
-                return true;
-            }
+            && let Some(ch) = line.char_at_col(pos.col)
+        {
+            self.delete_char_at_internal(pos);
+            self.dirty = true;
+            self.record_edit(TextEdit {
+                start: pos,
+                old_end: TextPosition {
+                    row: pos.row,
+                    col: pos.col + mame::terminal::char_cols(ch),
+                },
+                new_end: pos,
+            });
+            return true;
+        }
 
         // Handle forward delete at line end (merge with next line)
         if pos.col >= self.cols(pos.row) && pos.row < self.text.len().saturating_sub(1) {
@@ -61,6 +81,14 @@ impl TextBuffer {
                 if let Some(current_line) = self.text.get_mut(pos.row) {
                     current_line.extend_from_line(next_line);
                     self.dirty = true;
+                    self.record_edit(TextEdit {
+                        start: pos,
+                        old_end: TextPosition {
+                            row: pos.row + 1,
+                            col: 0,
+                        },
+                        new_end: pos,
+                    });
                     return true;
                 }
             }
@@ -86,13 +114,20 @@ impl TextBuffer {
                     && self.delete_char_at_internal(TextPosition {
                         row: pos.row,
                         col: char_pos,
-                    }) {
-                        self.dirty = true;
-                        return Some(TextPosition {
-                            row: pos.row,
-                            col: char_pos,
-                        });
-                    }
+                    })
+                {
+                    self.dirty = true;
+                    let new_pos = TextPosition {
+                        row: pos.row,
+                        col: char_pos,
+                    };
+                    self.record_edit(TextEdit {
+                        start: new_pos,
+                        old_end: pos,
+                        new_end: new_pos,
+                    });
+                    return Some(new_pos);
+                }
             }
         } else if pos.row > 0 {
             // Delete newline - merge with previous line
@@ -105,10 +140,16 @@ impl TextBuffer {
                 if let Some(prev_line) = self.text.get_mut(prev_row) {
                     prev_line.extend_from_line(current_line);
                     self.dirty = true;
-                    return Some(TextPosition {
+                    let new_pos = TextPosition {
                         row: prev_row,
                         col: prev_col,
+                    };
+                    self.record_edit(TextEdit {
+                        start: new_pos,
+                        old_end: pos,
+                        new_end: new_pos,
                     });
+                    return Some(new_pos);
                 }
             }
         }
@@ -134,6 +175,11 @@ impl TextBuffer {
     pub fn insert_char_at(&mut self, pos: TextPosition, ch: char) -> TextPosition {
         let new_pos = self.insert_char_at_internal(pos, ch);
         self.dirty = true;
+        self.record_edit(TextEdit {
+            start: pos,
+            old_end: pos,
+            new_end: new_pos,
+        });
         new_pos
     }
 
@@ -156,6 +202,117 @@ impl TextBuffer {
         }
     }
 
+    pub fn insert_str_at(&mut self, mut pos: TextPosition, s: &str) -> TextPosition {
+        // Unlike `str::lines()`, a trailing newline is kept
+        for (i, line) in s.split('\n').enumerate() {
+            let line = line.strip_suffix('\r').unwrap_or(line);
+            if i > 0 {
+                pos = self.insert_newline_at(pos);
+            }
+            for ch in line.chars() {
+                pos = self.insert_char_at(pos, ch);
+            }
+        }
+        pos
+    }
+
+    /// Replaces the whole text with `text` by editing only the lines that differ.
+    ///
+    /// Returns the replaced lines in the order the edits were made, for undoing them.
+    pub fn apply_line_diff(&mut self, text: &str) -> Vec<ReplacedLines> {
+        let new_lines = text
+            .lines()
+            .map(|l| TextLine(l.chars().collect()))
+            .collect::<Vec<_>>();
+        self.replace_lines(&new_lines)
+    }
+
+    /// Same as `apply_line_diff()`, but takes the new text as lines.
+    pub fn replace_lines(&mut self, new_lines: &[TextLine]) -> Vec<ReplacedLines> {
+        let hunks = crate::diff::diff_lines(&self.text, new_lines);
+        let mut replaced = Vec::new();
+        for hunk in hunks {
+            // Preceding hunks are already applied, so rows are in the new text from here
+            let row = hunk.new.start;
+            let old_rows = hunk.old.len();
+            let old_lines = self
+                .text
+                .splice(
+                    row..row + old_rows,
+                    new_lines[hunk.new.clone()].iter().cloned(),
+                )
+                .collect();
+            replaced.push(self.record_replaced_lines(row, old_rows, old_lines, hunk.new.len()));
+        }
+        replaced
+    }
+
+    /// Undoes the edits made by `apply_line_diff()`.
+    pub fn revert_lines(&mut self, replaced: &[ReplacedLines]) {
+        for r in replaced.iter().rev() {
+            let new_rows = r.old_lines.len();
+            let lines = self
+                .text
+                .splice(r.row..r.row + r.rows, r.old_lines.iter().cloned())
+                .collect();
+            self.record_replaced_lines(r.row, r.rows, lines, new_rows);
+        }
+    }
+
+    fn record_replaced_lines(
+        &mut self,
+        row: usize,
+        old_rows: usize,
+        old_lines: Vec<TextLine>,
+        new_rows: usize,
+    ) -> ReplacedLines {
+        self.dirty = true;
+        self.record_edit(TextEdit {
+            start: TextPosition { row, col: 0 },
+            old_end: TextPosition {
+                row: row + old_rows,
+                col: 0,
+            },
+            new_end: TextPosition {
+                row: row + new_rows,
+                col: 0,
+            },
+        });
+        ReplacedLines {
+            row,
+            rows: new_rows,
+            old_lines,
+        }
+    }
+
+    /// Returns a buffer holding only the text between `start` and `end`.
+    pub fn sub_buffer(&self, start: TextPosition, end: TextPosition) -> TextBuffer {
+        let Some(last_row) = self.rows().checked_sub(1) else {
+            return TextBuffer::default();
+        };
+        let mut text = Vec::new();
+        for row in start.row..=end.row.min(last_row) {
+            let line = &self.text[row];
+            let start_index = if row == start.row {
+                line.char_index_at_col(start.col)
+            } else {
+                0
+            };
+            let end_index = if row == end.row {
+                line.char_index_at_col(end.col)
+            } else {
+                line.len()
+            };
+            text.push(TextLine(
+                line.0[start_index..end_index.max(start_index)].to_vec(),
+            ));
+        }
+        TextBuffer {
+            text,
+            ..TextBuffer::default()
+        }
+    }
+
     pub fn col_at_char_index(&self, row: usize, char_index: usize) -> Option<usize> {
         self.text.get(row).map(|line| line.col_at_char_index(char_index))
     }
@@ -178,6 +335,11 @@ impl TextBuffer {
     pub fn insert_newline_at(&mut self, pos: TextPosition) -> TextPosition {
         let new_pos = self.insert_newline_at_internal(pos);
         self.dirty = true;
+        self.record_edit(TextEdit {
+            start: pos,
+            old_end: pos,
+            new_end: new_pos,
+        });
         new_pos
     }
 
@@ -219,10 +381,6 @@ impl TextLine {
         TextLine(chars)
     }
 
-    pub fn to_string(&self) -> String {
-        self.0.iter().collect()
-    }
-
     pub fn extend_from_line(&mut self, other: TextLine) {
         self.0.extend(other.0);
     }
@@ -255,11 +413,11 @@ impl TextLine {
         None
     }
 
-    fn cols(&self) -> usize {
+    pub fn cols(&self) -> usize {
         self.0.iter().copied().map(mame::terminal::char_cols).sum()
     }
 
-    fn adjust_to_char_boundary(&self, col: usize, floor: bool) -> usize {
+    pub fn adjust_to_char_boundary(&self, col: usize, floor: bool) -> usize {
         let mut start = 0;
         for &ch in &self.0 {
             let end = start + mame::terminal::char_cols(ch);
@@ -327,10 +485,42 @@ impl TextLine {
         self.0.len()
     }
 
+    /// Returns the char index range of the characters that fit entirely within `[start_col, end_col)`.
+    pub fn char_range_in_cols(&self, start_col: usize, end_col: usize) -> std::ops::Range<usize> {
+        let mut start = self.0.len();
+        let mut end = self.0.len();
+        for (i, (col, ch)) in self.char_cols().enumerate() {
+            if start == self.0.len() && col >= start_col {
+                start = i;
+            }
+            if col + mame::terminal::char_cols(ch) > end_col {
+                end = i;
+                break;
+            }
+        }
+        start..end.max(start)
+    }
+
+    pub fn pad_to_col(&mut self, col: usize) {
+        let cols = self.cols();
+        if cols < col {
+            self.0.extend(std::iter::repeat_n(' ', col - cols));
+        }
+    }
+
+    pub fn insert_chars_at_col(&mut self, col: usize, chars: &[char]) {
+        let char_index = self.char_index_at_col(col);
+        self.0.splice(char_index..char_index, chars.iter().copied());
+    }
+
     pub fn len(&self) -> usize {
         self.0.len()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
     pub fn col_at_char_index(&self, char_index: usize) -> usize {
         let mut col = 0;
         for (i, &ch) in self.0.iter().enumerate() {
@@ -343,8 +533,193 @@ impl TextLine {
     }
 }
 
+impl std::fmt::Display for TextLine {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for ch in &self.0 {
+            write!(f, "{ch}")?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct TextPosition {
     pub row: usize, // 0 origin
     pub col: usize, // 0 origin
 }
+
+/// Lines `row..row + rows` that replaced `old_lines`.
+#[derive(Debug, Clone)]
+pub struct ReplacedLines {
+    pub row: usize,
+    pub rows: usize,
+    pub old_lines: Vec<TextLine>,
+}
+
+/// A change that replaced the text between `start` and `old_end` with text ending at `new_end`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TextEdit {
+    pub start: TextPosition,
+    pub old_end: TextPosition,
+    pub new_end: TextPosition,
+}
+
+impl TextEdit {
+    /// Maps a position in the text before this edit to the corresponding position after it.
+    pub fn shift_position(&self, pos: TextPosition) -> TextPosition {
+        if pos < self.old_end {
+            if pos <= self.start {
+                pos
+            } else {
+                // The position was inside the replaced text
+                self.new_end
+            }
+        } else if pos.row == self.old_end.row {
+            TextPosition {
+                row: self.new_end.row,
+                col: self.new_end.col + (pos.col - self.old_end.col),
+            }
+        } else {
+            TextPosition {
+                row: pos.row - self.old_end.row + self.new_end.row,
+                col: pos.col,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buffer(text: &str) -> TextBuffer {
+        TextBuffer {
+            text: text
+                .lines()
+                .map(|l| TextLine(l.chars().collect()))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn pos(row: usize, col: usize) -> TextPosition {
+        TextPosition { row, col }
+    }
+
+    #[test]
+    fn shift_position_by_insertion() {
+        // "xy\nz" inserted at (0, 3)
+        let edit = TextEdit {
+            start: pos(0, 3),
+            old_end: pos(0, 3),
+            new_end: pos(1, 1),
+        };
+        assert_eq!(edit.shift_position(pos(0, 2)), pos(0, 2));
+        assert_eq!(edit.shift_position(pos(0, 3)), pos(1, 1));
+        assert_eq!(edit.shift_position(pos(0, 5)), pos(1, 3));
+        assert_eq!(edit.shift_position(pos(2, 4)), pos(3, 4));
+    }
+
+    #[test]
+    fn shift_position_by_deletion() {
+        // The text from (0, 2) to (1, 1) deleted
+        let edit = TextEdit {
+            start: pos(0, 2),
+            old_end: pos(1, 1),
+            new_end: pos(0, 2),
+        };
+        assert_eq!(edit.shift_position(pos(0, 1)), pos(0, 1));
+        assert_eq!(edit.shift_position(pos(0, 2)), pos(0, 2));
+        assert_eq!(edit.shift_position(pos(0, 5)), pos(0, 2));
+        assert_eq!(edit.shift_position(pos(1, 1)), pos(0, 2));
+        assert_eq!(edit.shift_position(pos(1, 4)), pos(0, 5));
+        assert_eq!(edit.shift_position(pos(3, 0)), pos(2, 0));
+    }
+
+    fn line(text: &str) -> TextLine {
+        TextLine(text.chars().collect())
+    }
+
+    #[test]
+    fn char_range_in_cols_with_wide_chars() {
+        // 'あ' occupies columns 1 and 2
+        let line = line("aあb");
+        assert_eq!(line.char_range_in_cols(0, 4), 0..3);
+        assert_eq!(line.char_range_in_cols(1, 3), 1..2);
+
+        // A wide character is only included if it fits entirely
+        assert_eq!(line.char_range_in_cols(0, 2), 0..1);
+        assert_eq!(line.char_range_in_cols(2, 4), 2..3);
+
+        // Columns beyond the end of the line
+        assert_eq!(line.char_range_in_cols(3, 10), 2..3);
+        assert_eq!(line.char_range_in_cols(5, 8), 3..3);
+    }
+
+    #[test]
+    fn pad_short_line_to_col() {
+        let mut short = line("ab");
+        short.pad_to_col(4);
+        assert_eq!(short, line("ab  "));
+
+        let mut wide = line("aあ");
+        wide.pad_to_col(2);
+        assert_eq!(wide, line("aあ"));
+        wide.pad_to_col(4);
+        assert_eq!(wide, line("aあ "));
+    }
+
+    #[test]
+    fn insert_chars_at_col_with_wide_chars() {
+        let mut line1 = line("aあb");
+        line1.insert_chars_at_col(1, &['x', 'y']);
+        assert_eq!(line1, line("axyあb"));
+
+        let mut line2 = line("aあb");
+        line2.insert_chars_at_col(3, &['い']);
+        assert_eq!(line2, line("aあいb"));
+
+        // Appending to a line padded up to the insertion column
+        let mut short = line("a");
+        short.pad_to_col(3);
+        short.insert_chars_at_col(3, &['z']);
+        assert_eq!(short, line("a  z"));
+    }
+
+    #[test]
+    fn apply_and_revert_line_diff() {
+        let old = "a\nb\nc\nd\n";
+        let new = "a\nB\nc\nd\ne\n";
+        let mut buffer = buffer(old);
+        let replaced = buffer.apply_line_diff(new);
+        assert_eq!(buffer.to_single_text(), new);
+        assert_eq!(replaced.len(), 2);
+        assert!(buffer.dirty);
+
+        buffer.revert_lines(&replaced);
+        assert_eq!(buffer.to_single_text(), old);
+    }
+
+    #[test]
+    fn line_diff_journal() {
+        let mut buffer = buffer("a\nb\nc\n");
+        buffer.apply_line_diff("x\na\nc\n");
+        let edits = buffer.take_journal();
+
+        // A position on an unchanged line follows the line
+        let pos = TextPosition { row: 2, col: 1 };
+        let shifted = edits.iter().fold(pos, |pos, edit| edit.shift_position(pos));
+        assert_eq!(shifted, pos);
+        let pos = TextPosition { row: 0, col: 1 };
+        let shifted = edits.iter().fold(pos, |pos, edit| edit.shift_position(pos));
+        assert_eq!(shifted, TextPosition { row: 1, col: 1 });
+    }
+
+    #[test]
+    fn unchanged_line_diff() {
+        let mut buffer = buffer("a\nb\n");
+        assert!(buffer.apply_line_diff("a\nb\n").is_empty());
+        assert!(!buffer.dirty);
+        assert!(buffer.journal.is_empty());
+    }
+}
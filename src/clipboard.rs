@@ -4,7 +4,7 @@ use orfail::OrFail;
 
 #[derive(Debug)]
 pub struct Clipboard {
-    path: PathBuf,
+    pub path: PathBuf,
     pub summary_line: String,
 }
 
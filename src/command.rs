@@ -0,0 +1,261 @@
+//! Running external commands without blocking the editor indefinitely.
+use std::{
+    io::{Read, Write},
+    os::unix::process::ExitStatusExt,
+    path::{Path, PathBuf},
+    process::{Command, ExitStatus, Stdio},
+    sync::{Arc, Mutex, mpsc},
+    time::{Duration, Instant},
+};
+
+use mame::command::{CommandInput, CommandOutput, ExternalCommand};
+use orfail::OrFail;
+
+use crate::{
+    action::{CommandCwd, CommandEnv},
+    anchor::absolute_path,
+};
+
+/// How often the interrupt check is performed while waiting for a command.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Grace period for collecting the output of a killed command.
+///
+/// Grandchildren may keep the output pipes open after the command itself has been killed.
+const KILL_GRACE_PERIOD: Duration = Duration::from_millis(200);
+
+#[derive(Debug)]
+pub struct CommandRun {
+    pub status: ExitStatus,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    pub end: CommandEnd,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandEnd {
+    Exited,
+    TimedOut(Duration),
+    Interrupted,
+}
+
+impl CommandRun {
+    pub fn success(&self) -> bool {
+        self.end == CommandEnd::Exited && self.status.success()
+    }
+
+    /// Describes how the command ended, e.g. "[1] `$ make` exited".
+    pub fn summary(&self, name: impl std::fmt::Display) -> String {
+        match self.end {
+            CommandEnd::TimedOut(timeout) => {
+                format!("`$ {name}` killed by timeout ({} ms)", timeout.as_millis())
+            }
+            CommandEnd::Interrupted => format!("`$ {name}` interrupted"),
+            CommandEnd::Exited => exit_summary(self.status, name),
+        }
+    }
+}
+
+/// Describes an exit status, e.g. "[1] `$ make` exited" or "`$ make` killed by signal 9".
+pub fn exit_summary(status: ExitStatus, name: impl std::fmt::Display) -> String {
+    match (status.code(), status.signal()) {
+        (Some(code), _) => format!("[{code}] `$ {name}` exited"),
+        (None, Some(signal)) => format!("`$ {name}` killed by signal {signal}"),
+        (None, None) => format!("`$ {name}` exited"),
+    }
+}
+
+/// Applies the working directory and environment variables of `env` to `cmd`.
+///
+/// Returns the working directory if it was specified.
+pub fn apply_command_env(
+    cmd: &mut Command,
+    env: &CommandEnv,
+    current_file: &Path,
+) -> Option<PathBuf> {
+    cmd.envs(&env.env);
+    let dir = resolve_cwd(env.cwd.as_ref()?, current_file);
+    cmd.current_dir(&dir);
+    Some(dir)
+}
+
+pub fn resolve_cwd(cwd: &CommandCwd, current_file: &Path) -> PathBuf {
+    let file_dir = absolute_path(current_file)
+        .parent()
+        .map(Path::to_path_buf)
+        .unwrap_or_default();
+    match cwd {
+        CommandCwd::Path(path) => path.clone(),
+        CommandCwd::CurrentFileDir => file_dir,
+        CommandCwd::ProjectRoot { markers } => file_dir
+            .ancestors()
+            .find(|dir| markers.iter().any(|marker| dir.join(marker).exists()))
+            .map(Path::to_path_buf)
+            .unwrap_or(file_dir), // No project: the file's directory is the best guess
+    }
+}
+
+/// Runs `cmd` with `stdin` as its input until it exits, `timeout` elapses, or `interrupted` returns `true`.
+///
+/// `interrupted` is called repeatedly while waiting, and may block up to the given duration.
+pub fn run_command<F>(
+    mut cmd: Command,
+    stdin: Option<Vec<u8>>,
+    timeout: Option<Duration>,
+    mut interrupted: F,
+) -> orfail::Result<CommandRun>
+where
+    F: FnMut(Duration) -> orfail::Result<bool>,
+{
+    cmd.stdin(Stdio::piped());
+    cmd.stdout(Stdio::piped());
+    cmd.stderr(Stdio::piped());
+    let mut child = cmd.spawn().or_fail()?;
+
+    // Pipes are serviced by threads so that a command blocked on writing its output can proceed
+    if let Some(mut pipe) = child.stdin.take() {
+        std::thread::spawn(move || {
+            if let Some(input) = stdin {
+                let _ = pipe.write_all(&input);
+            }
+        });
+    }
+    let stdout = read_in_background(child.stdout.take());
+    let stderr = read_in_background(child.stderr.take());
+
+    let start = Instant::now();
+    let mut end = CommandEnd::Exited;
+    let status = loop {
+        if let Some(status) = child.try_wait().or_fail()? {
+            break status;
+        }
+        if let Some(timeout) = timeout
+            && start.elapsed() >= timeout
+        {
+            end = CommandEnd::TimedOut(timeout);
+        } else if interrupted(POLL_INTERVAL).or_fail()? {
+            end = CommandEnd::Interrupted;
+        } else {
+            continue;
+        }
+        let _ = child.kill();
+        break child.wait().or_fail()?;
+    };
+
+    let deadline = (end != CommandEnd::Exited).then(|| Instant::now() + KILL_GRACE_PERIOD);
+    Ok(CommandRun {
+        status,
+        stdout: receive_output(stdout, deadline),
+        stderr: receive_output(stderr, deadline),
+        end,
+    })
+}
+
+// Output read so far, and a channel closed when the pipe reaches EOF
+type OutputReader = (Arc<Mutex<Vec<u8>>>, mpsc::Receiver<()>);
+
+fn read_in_background<R: Read + Send + 'static>(pipe: Option<R>) -> OutputReader {
+    let output = Arc::new(Mutex::new(Vec::new()));
+    let (tx, rx) = mpsc::channel();
+    if let Some(mut pipe) = pipe {
+        let output = output.clone();
+        std::thread::spawn(move || {
+            let _tx = tx; // Dropped at EOF
+            let mut buf = [0; 8192];
+            while let Ok(n) = pipe.read(&mut buf)
+                && n > 0
+            {
+                output
+                    .lock()
+                    .expect("poisoned")
+                    .extend_from_slice(&buf[..n]);
+            }
+        });
+    }
+    (output, rx)
+}
+
+fn receive_output((output, rx): OutputReader, deadline: Option<Instant>) -> Vec<u8> {
+    // Both calls return once the reader thread ends
+    if let Some(deadline) = deadline {
+        let _ = rx.recv_timeout(deadline.saturating_duration_since(Instant::now()));
+    } else {
+        let _ = rx.recv();
+    }
+    std::mem::take(&mut *output.lock().expect("poisoned"))
+}
+
+/// Runs `mame`'s command like [`ExternalCommand::execute()`], but through [`run_command()`].
+///
+/// Relative file paths of `command` are taken as relative to `dir`, like the command's own paths.
+pub fn run_mame_command<F>(
+    command: &ExternalCommand,
+    dir: Option<&Path>,
+    timeout: Option<Duration>,
+    interrupted: F,
+) -> orfail::Result<CommandRun>
+where
+    F: FnMut(Duration) -> orfail::Result<bool>,
+{
+    let mut cmd = Command::new(&command.command);
+    cmd.args(&command.args);
+    cmd.envs(&command.envs);
+    if let Some(dir) = dir {
+        cmd.current_dir(dir);
+    }
+    let stdin = match &command.stdin {
+        CommandInput::Null => None,
+        CommandInput::Text { text } => Some(text.clone().into_bytes()),
+        CommandInput::File { path } => {
+            let path = in_dir(path, dir);
+            Some(
+                std::fs::read(&path)
+                    .or_fail_with(|e| format!("failed to read {}: {e}", path.display()))?,
+            )
+        }
+    };
+
+    let run = run_command(cmd, stdin, timeout, interrupted).or_fail()?;
+    let success = run.success();
+    write_output(&command.stdout, &run.stdout, success, dir).or_fail()?;
+    write_output(&command.stderr, &run.stderr, success, dir).or_fail()?;
+    Ok(run)
+}
+
+fn in_dir(path: &Path, dir: Option<&Path>) -> PathBuf {
+    match dir {
+        Some(dir) => dir.join(path),
+        None => path.to_path_buf(),
+    }
+}
+
+fn write_output(
+    output: &CommandOutput,
+    bytes: &[u8],
+    success: bool,
+    dir: Option<&Path>,
+) -> orfail::Result<()> {
+    let CommandOutput::File {
+        path,
+        append,
+        skip_if_empty,
+        skip_if_success,
+    } = output
+    else {
+        return Ok(());
+    };
+    if (*skip_if_empty && bytes.is_empty()) || (*skip_if_success && success) {
+        return Ok(());
+    }
+
+    let path = in_dir(path, dir);
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(!*append)
+        .append(*append)
+        .open(&path)
+        .or_fail_with(|e| format!("failed to open {}: {e}", path.display()))?;
+    file.write_all(bytes).or_fail()?;
+    Ok(())
+}
@@ -0,0 +1,179 @@
+//! Line diff based on Myers' O(ND) algorithm.
+//!
+//! Used to apply the output of formatters and reloads as small edits,
+//! so that the cursor, the mark and anchors outside the changed lines stay where they are.
+use std::ops::Range;
+
+/// Beyond this many inserted and deleted lines, the changed part is treated as a single hunk.
+const MAX_EDIT_DISTANCE: usize = 2000;
+
+/// Lines `old` of the old text that were replaced with lines `new` of the new text.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hunk {
+    pub old: Range<usize>,
+    pub new: Range<usize>,
+}
+
+/// Returns the hunks that turn `old` into `new`, in ascending order.
+pub fn diff_lines<T: PartialEq>(old: &[T], new: &[T]) -> Vec<Hunk> {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let a = &old[prefix..old.len() - suffix];
+    let b = &new[prefix..new.len() - suffix];
+
+    // If the texts are too different, the whole middle part is replaced
+    let matches = shortest_edit_matches(a, b).unwrap_or_default();
+
+    let mut hunks = Vec::new();
+    let (mut x, mut y) = (0, 0);
+    for (mx, my) in matches
+        .into_iter()
+        .chain(std::iter::once((a.len(), b.len())))
+    {
+        if mx > x || my > y {
+            hunks.push(Hunk {
+                old: prefix + x..prefix + mx,
+                new: prefix + y..prefix + my,
+            });
+        }
+        (x, y) = (mx + 1, my + 1);
+    }
+    hunks
+}
+
+// Returns the index pairs of the lines kept in both texts, in ascending order
+fn shortest_edit_matches<T: PartialEq>(a: &[T], b: &[T]) -> Option<Vec<(usize, usize)>> {
+    let n = a.len() as isize;
+    let m = b.len() as isize;
+    let max = (a.len() + b.len()).min(MAX_EDIT_DISTANCE) as isize;
+
+    // `v[k]` is the furthest x reached on diagonal k (= x - y).
+    // `trace[d]` keeps `v[-d..=d]` after step d for backtracking.
+    let mut v = vec![0isize; 2 * max as usize + 3];
+    let at = |k: isize| (k + max + 1) as usize;
+    let mut trace: Vec<Vec<isize>> = Vec::new();
+    let mut found = false;
+    for d in 0..=max {
+        for k in (-d..=d).step_by(2) {
+            let mut x = if k == -d || (k != d && v[at(k - 1)] < v[at(k + 1)]) {
+                v[at(k + 1)]
+            } else {
+                v[at(k - 1)] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[at(k)] = x;
+            if x >= n && y >= m {
+                found = true;
+            }
+        }
+        trace.push(v[at(-d)..=at(d)].to_vec());
+        if found {
+            break;
+        }
+    }
+    if !found {
+        return None;
+    }
+
+    let mut matches = Vec::new();
+    let (mut x, mut y) = (n, m);
+    for d in (0..trace.len() as isize).rev() {
+        // Position at the start of the diagonal run (matching lines) that ended this step
+        let (start_x, start_y, prev) = if d == 0 {
+            (0, 0, (0, 0))
+        } else {
+            let prev = &trace[d as usize - 1];
+            let get = |k: isize| prev[(k + d - 1) as usize];
+            let k = x - y;
+            if k == -d || (k != d && get(k - 1) < get(k + 1)) {
+                // Insertion: moved down from diagonal k + 1
+                let prev_x = get(k + 1);
+                let prev_y = prev_x - (k + 1);
+                (prev_x, prev_y + 1, (prev_x, prev_y))
+            } else {
+                // Deletion: moved right from diagonal k - 1
+                let prev_x = get(k - 1);
+                let prev_y = prev_x - (k - 1);
+                (prev_x + 1, prev_y, (prev_x, prev_y))
+            }
+        };
+        while x > start_x && y > start_y {
+            x -= 1;
+            y -= 1;
+            matches.push((x as usize, y as usize));
+        }
+        (x, y) = prev;
+    }
+    matches.reverse();
+    Some(matches)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hunk(old: Range<usize>, new: Range<usize>) -> Hunk {
+        Hunk { old, new }
+    }
+
+    #[test]
+    fn unchanged() {
+        assert_eq!(diff_lines::<&str>(&[], &[]), []);
+        assert_eq!(diff_lines(&["a", "b"], &["a", "b"]), []);
+    }
+
+    #[test]
+    fn insertions_and_deletions() {
+        assert_eq!(diff_lines(&[], &["a"]), [hunk(0..0, 0..1)]);
+        assert_eq!(diff_lines(&["a"], &[]), [hunk(0..1, 0..0)]);
+        assert_eq!(
+            diff_lines(&["a", "c"], &["a", "b", "c"]),
+            [hunk(1..1, 1..2)]
+        );
+        assert_eq!(
+            diff_lines(&["a", "b", "c"], &["a", "c"]),
+            [hunk(1..2, 1..1)]
+        );
+    }
+
+    #[test]
+    fn replacements() {
+        assert_eq!(
+            diff_lines(&["a", "b", "c"], &["a", "x", "c"]),
+            [hunk(1..2, 1..2)]
+        );
+        assert_eq!(
+            diff_lines(&["a", "b", "c", "d", "e"], &["x", "b", "c", "y", "z", "e"]),
+            [hunk(0..1, 0..1), hunk(3..4, 3..5)]
+        );
+    }
+
+    #[test]
+    fn hunks_turn_old_into_new() {
+        let old = ["fn main() {", "  a();", "  b();", "}", "", "fn f() {}"];
+        let new = [
+            "fn main() {",
+            "    a();",
+            "    b();",
+            "    c();",
+            "}",
+            "fn f() {}",
+        ];
+        let mut text = old.to_vec();
+        for hunk in diff_lines(&old, &new) {
+            // Preceding hunks are already applied, so rows are in the new text
+            let start = hunk.new.start;
+            text.splice(start..start + hunk.old.len(), new[hunk.new].iter().copied());
+        }
+        assert_eq!(text, new);
+    }
+}
@@ -0,0 +1,266 @@
+//! Locations of compiler and linter diagnostics, found in command output.
+use std::{
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+};
+
+use crate::{action::ErrorPattern, anchor::CursorAnchor};
+
+#[derive(Debug)]
+pub struct ErrorList {
+    pub source: String,    // Where the errors were read from, for display
+    pub from_output: bool, // Whether read from the output pane rather than a file
+    pub errors: Vec<ErrorEntry>,
+    pub index: Option<usize>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ErrorEntry {
+    pub anchor: CursorAnchor,
+    pub message: String, // e.g., "error[E0425]: cannot find value `x` in this scope"
+    pub row: usize,      // Index of the line in the scanned text
+}
+
+impl ErrorList {
+    /// Scans `text` line by line, trying `patterns` in order on each line.
+    ///
+    /// Relative paths are taken as relative to `dir` if given.
+    pub fn scan(source: String, text: &str, patterns: &[ErrorPattern], dir: Option<&Path>) -> Self {
+        let mut errors = Vec::new();
+        let mut header = ""; // Latest rustc diagnostic line, such as "warning: unused variable"
+        for (row, line) in text.lines().enumerate() {
+            if !line.starts_with(char::is_whitespace) && !line.trim().is_empty() {
+                header = line;
+            }
+            if let Some(mut entry) = patterns
+                .iter()
+                .find_map(|p| match_line(p, line, header, row))
+            {
+                if let Some(dir) = dir {
+                    entry.anchor = entry.anchor.in_dir(dir);
+                }
+                errors.push(entry);
+            }
+        }
+        Self {
+            source,
+            from_output: false,
+            errors,
+            index: None,
+        }
+    }
+
+    /// Returns the row of the current error in the scanned text.
+    pub fn current_row(&self) -> Option<usize> {
+        self.index.map(|i| self.errors[i].row)
+    }
+}
+
+fn match_line(pattern: &ErrorPattern, line: &str, header: &str, row: usize) -> Option<ErrorEntry> {
+    match pattern {
+        ErrorPattern::Rustc => {
+            // FORMAT: --> <PATH>:<LINE>:<COL>
+            let location = line.trim_start().strip_prefix("--> ")?;
+            let mut tokens = location.trim_end().rsplitn(3, ':');
+            let char = tokens.next()?.parse().ok()?;
+            let line = tokens.next()?.parse().ok()?;
+            let path = tokens.next().filter(|p| !p.is_empty())?;
+            Some(ErrorEntry {
+                anchor: CursorAnchor {
+                    path: PathBuf::from(path),
+                    line,
+                    char,
+                },
+                message: header.to_owned(),
+                row,
+            })
+        }
+        ErrorPattern::Gcc => {
+            // FORMAT: <PATH>:<LINE>:<COL>: <MESSAGE>
+            if line.starts_with(char::is_whitespace) {
+                return None;
+            }
+            let mut tokens = line.splitn(4, ':');
+            let path = tokens.next().filter(|p| !p.is_empty())?;
+            let line = tokens.next()?.parse().ok()?;
+            let char = tokens.next()?.parse().ok()?;
+            let message = tokens.next()?;
+            Some(ErrorEntry {
+                anchor: CursorAnchor {
+                    path: PathBuf::from(path),
+                    line,
+                    char,
+                },
+                message: message.trim().to_owned(),
+                row,
+            })
+        }
+        ErrorPattern::Regex {
+            regex,
+            path,
+            line: line_group,
+            column,
+        } => {
+            let captures = regex.captures(line)?;
+            let group = |i: usize| captures.get(i).map(|m| m.as_str());
+            let path = group(*path).filter(|p| !p.is_empty())?;
+            let line_number = group(*line_group)?.parse().ok()?;
+            let char = group(*column)
+                .and_then(|c| c.parse().ok())
+                .unwrap_or(NonZeroUsize::MIN);
+            Some(ErrorEntry {
+                anchor: CursorAnchor {
+                    path: PathBuf::from(path),
+                    line: line_number,
+                    char,
+                },
+                message: line.trim().to_owned(),
+                row,
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn locations(
+        text: &str,
+        patterns: &[ErrorPattern],
+        dir: Option<&Path>,
+    ) -> Vec<(String, usize, usize, usize)> {
+        ErrorList::scan(String::new(), text, patterns, dir)
+            .errors
+            .into_iter()
+            .map(|e| {
+                let path = e.anchor.path.display().to_string();
+                (path, e.anchor.line.get(), e.anchor.char.get(), e.row)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn match_rustc_locations() {
+        let cases = [
+            ("  --> src/main.rs:3:5", Some(("src/main.rs", 3, 5))),
+            ("   --> /abs/lib.rs:10:1  ", Some(("/abs/lib.rs", 10, 1))),
+            ("--> C:/win/a.rs:1:2", Some(("C:/win/a.rs", 1, 2))),
+            ("  --> src/main.rs:3", None),
+            ("  --> :3:5", None),
+            ("  -> src/main.rs:3:5", None),
+            ("src/main.rs:3:5: error", None),
+        ];
+        for (line, expected) in cases {
+            let found = locations(line, &[ErrorPattern::Rustc], None);
+            let expected: Vec<_> = expected
+                .into_iter()
+                .map(|(p, l, c)| (p.to_owned(), l, c, 0))
+                .collect();
+            assert_eq!(found, expected, "{line:?}");
+        }
+    }
+
+    #[test]
+    fn match_gcc_locations() {
+        let cases = [
+            (
+                "foo.c:10:2: error: expected ';'",
+                Some(("foo.c", 10, 2, "error: expected ';'")),
+            ),
+            (
+                "/abs/foo.h:1:1: note: here",
+                Some(("/abs/foo.h", 1, 1, "note: here")),
+            ),
+            ("foo.c:10: error: no column", None),
+            ("foo.c:0:2: error: line zero", None),
+            ("  foo.c:10:2: indented", None),
+            ("In file included from foo.c:3:", None),
+        ];
+        for (line, expected) in cases {
+            let list = ErrorList::scan(String::new(), line, &[ErrorPattern::Gcc], None);
+            let found: Vec<_> = list
+                .errors
+                .iter()
+                .map(|e| {
+                    let path = e.anchor.path.display().to_string();
+                    (
+                        path,
+                        e.anchor.line.get(),
+                        e.anchor.char.get(),
+                        e.message.as_str(),
+                    )
+                })
+                .collect();
+            let expected: Vec<_> = expected
+                .into_iter()
+                .map(|(p, l, c, m)| (p.to_owned(), l, c, m))
+                .collect();
+            assert_eq!(found, expected, "{line:?}");
+        }
+    }
+
+    #[test]
+    fn rustc_message_and_rows() {
+        let text = "\
+warning: unused variable: `x`
+ --> src/main.rs:2:9
+  |
+error[E0425]: cannot find value `y` in this scope
+ --> src/lib.rs:7:13
+foo.c:1:1: error: gcc style
+";
+        let list = ErrorList::scan(String::new(), text, &ErrorPattern::defaults(), None);
+        let found: Vec<_> = list
+            .errors
+            .iter()
+            .map(|e| (e.row, e.message.as_str()))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                (1, "warning: unused variable: `x`"),
+                (4, "error[E0425]: cannot find value `y` in this scope"),
+                (5, "error: gcc style"),
+            ]
+        );
+    }
+
+    #[test]
+    fn resolve_relative_paths_against_dir() {
+        let text = " --> src/main.rs:1:1\n --> /abs/lib.rs:2:3\nfoo.c:4:5: error\n";
+        let dir = Path::new("/nonexistent-kk-dir/project");
+        assert_eq!(
+            locations(text, &ErrorPattern::defaults(), Some(dir)),
+            [
+                (
+                    "/nonexistent-kk-dir/project/src/main.rs".to_owned(),
+                    1,
+                    1,
+                    0
+                ),
+                ("/abs/lib.rs".to_owned(), 2, 3, 1),
+                ("/nonexistent-kk-dir/project/foo.c".to_owned(), 4, 5, 2),
+            ]
+        );
+        assert_eq!(
+            locations(text, &ErrorPattern::defaults(), None)[0].0,
+            "src/main.rs"
+        );
+    }
+
+    #[test]
+    fn match_regex_locations() {
+        let pattern = ErrorPattern::Regex {
+            regex: regex::Regex::new(r"^(\S+)\((\d+)(?:,(\d+))?\)").expect("valid regex"),
+            path: 1,
+            line: 2,
+            column: 3,
+        };
+        let text = "a.ts(3,7): error\nb.ts(4): warning\nc.ts(x,1): bad\n";
+        assert_eq!(
+            locations(text, &[pattern], None),
+            [("a.ts".to_owned(), 3, 7, 0), ("b.ts".to_owned(), 4, 1, 1)]
+        );
+    }
+}
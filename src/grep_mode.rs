@@ -1,43 +1,154 @@
-use std::{fmt::Write, io::Write as _, path::PathBuf};
+use std::{
+    fmt::Write,
+    io::Read,
+    os::fd::{AsRawFd, RawFd},
+    path::{Path, PathBuf},
+};
 
-use mame::terminal::UnicodeTerminalFrame as TerminalFrame;
+use mame::{
+    preview::{TextPreview, TextPreviewPane},
+    terminal::{UnicodeTerminalFrame as TerminalFrame, str_cols},
+};
 use orfail::OrFail;
+use regex::Regex;
 use tuinix::{TerminalPosition, TerminalRegion};
 
 use crate::{
-    action::GrepAction,
+    action::{BuiltinGrepMode, ExternalGrepSyntax, GrepAction, GrepEngine, GrepHistory, GrepScope},
+    anchor::CursorAnchor,
     buffer::{TextBuffer, TextPosition},
+    command::apply_command_env,
+    line_editor::LineEditor,
+    line_history::LineHistory,
     state::State,
 };
 
 #[derive(Debug)]
 pub struct GrepMode {
     pub action: GrepAction,
-    pub query: Vec<char>,
-    pub cursor: usize,
-    query_history_index: Option<usize>,
+    pub query: LineEditor,
+    pub mark_region: Option<(TextPosition, TextPosition)>, // Marked region when grep mode started
+    pub confirm_replaced: Option<usize>, // Number of replacements while confirming each hit
+    pub project_hits: Vec<ProjectHit>,
+    pub project_hit_index: Option<usize>,
+    query_history: LineHistory,
+    process: Option<GrepProcess>, // Running external grep command, if any
 }
 
 impl GrepMode {
-    pub fn new(action: GrepAction) -> Self {
+    pub fn new(
+        action: GrepAction,
+        mark_region: Option<(TextPosition, TextPosition)>,
+        context: &str,
+    ) -> Self {
+        let history_name = match action.history {
+            GrepHistory::Shared => None,
+            GrepHistory::Command => match action.engine {
+                GrepEngine::External { .. } => Some(action.command.as_str()),
+                GrepEngine::Builtin { .. } => Some("builtin"),
+            },
+            GrepHistory::Context => Some(context.trim_start_matches('@')),
+        };
+        let query_history = LineHistory::new(".kk.grep-queries", history_name);
         Self {
             action,
-            query: Vec::new(),
-            cursor: 0,
-            query_history_index: None,
+            query: LineEditor::default(),
+            mark_region,
+            confirm_replaced: None,
+            project_hits: Vec::new(),
+            project_hit_index: None,
+            query_history,
+            process: None,
+        }
+    }
+
+    /// Returns the hits that lie within the marked region, or all hits if there was no mark.
+    pub fn target_hits<'a>(
+        &self,
+        highlight: &'a Highlight,
+    ) -> impl Iterator<Item = &'a HighlightItem> {
+        let region = self.mark_region;
+        highlight.items.iter().filter(move |item| {
+            region
+                .is_none_or(|(start, end)| start <= item.start_position && item.end_position <= end)
+        })
+    }
+
+    /// Compiles the query as the grep engine interprets it.
+    pub fn query_regex(&self) -> orfail::Result<Regex> {
+        let query = self.query.to_text();
+        let (pattern, ignore_case) = match self.action.engine {
+            GrepEngine::Builtin {
+                mode: BuiltinGrepMode::Literal,
+                ignore_case,
+            }
+            | GrepEngine::External {
+                syntax: ExternalGrepSyntax::Fixed,
+                ignore_case,
+            } => (regex::escape(&query), ignore_case),
+            GrepEngine::External {
+                syntax: ExternalGrepSyntax::Basic,
+                ignore_case,
+            } => (basic_to_extended(&query), ignore_case),
+            GrepEngine::Builtin { ignore_case, .. } | GrepEngine::External { ignore_case, .. } => {
+                (query, ignore_case)
+            }
+        };
+        regex::RegexBuilder::new(&pattern)
+            .case_insensitive(ignore_case)
+            .build()
+            .or_fail()
+    }
+
+    /// Compiles the query for expanding `\N` in replacements.
+    ///
+    /// Only the builtin engine is supported, as the hits of an external grep command
+    /// (e.g., POSIX leftmost-longest matches) may differ from the matches of [`Regex`].
+    pub fn capture_regex(&self) -> Option<orfail::Result<Regex>> {
+        match self.action.engine {
+            GrepEngine::Builtin { .. } => Some(self.query_regex()),
+            GrepEngine::External { .. } => None,
+        }
+    }
+
+    /// Inserts `text` into the query so that it is searched literally.
+    pub fn insert_literal(&mut self, text: &str) -> bool {
+        let special = match self.action.engine {
+            GrepEngine::Builtin {
+                mode: BuiltinGrepMode::Regex,
+                ..
+            } => return self.query.insert_str(&regex::escape(text)),
+            GrepEngine::Builtin {
+                mode: BuiltinGrepMode::Literal,
+                ..
+            }
+            | GrepEngine::External {
+                syntax: ExternalGrepSyntax::Fixed,
+                ..
+            } => "",
+            GrepEngine::External {
+                syntax: ExternalGrepSyntax::Basic,
+                ..
+            } => "\\.[]*^$",
+            // Escaping punctuation with a backslash is also valid in Perl syntax
+            GrepEngine::External { .. } => "\\.[]*^$+?(){}|",
+        };
+        let mut escaped = String::with_capacity(text.len());
+        for ch in text.chars() {
+            if special.contains(ch) {
+                escaped.push('\\');
+            }
+            escaped.push(ch);
         }
+        self.query.insert_str(&escaped)
     }
 
     pub fn cursor_position(&self, region: TerminalRegion) -> TerminalPosition {
         let mut frame = TerminalFrame::new(region.size);
         let mut pos = region.position;
 
-        // TODO: factor out
-        let _ = write!(frame, "$ {} ", self.action.command);
-        for arg in &self.action.args {
-            let _ = write!(frame, "{arg} ");
-        }
-        for ch in self.query.iter().take(self.cursor) {
+        let _ = write!(frame, "{}", self.prompt());
+        for ch in self.query.text.iter().take(self.query.cursor) {
             let _ = write!(frame, "{ch}");
         }
         pos.col = frame.cursor().col;
@@ -45,116 +156,423 @@ impl GrepMode {
         pos
     }
 
-    pub fn handle_char_insert(&mut self, key: tuinix::KeyInput) {
-        let tuinix::KeyCode::Char(ch) = key.code else {
-            return;
-        };
-        self.query.insert(self.cursor, ch);
-        self.cursor += 1;
+    fn prompt(&self) -> String {
+        match self.action.engine {
+            GrepEngine::External { .. } => {
+                let mut prompt = format!("$ {} ", self.action.command);
+                for arg in &self.action.args {
+                    let _ = write!(prompt, "{arg} ");
+                }
+                prompt
+            }
+            GrepEngine::Builtin { mode, ignore_case } => {
+                let mode = match mode {
+                    BuiltinGrepMode::Literal => "literal",
+                    BuiltinGrepMode::Regex => "regex",
+                };
+                let case = if ignore_case { ",ignore-case" } else { "" };
+                format!("[{mode}{case}] ")
+            }
+        }
     }
 
-    pub fn grep(&mut self, buffer: &TextBuffer) -> orfail::Result<Highlight> {
+    /// Starts searching the query.
+    ///
+    /// Returns `None` if an external command has been spawned.
+    /// In that case, the result is obtained by [`GrepMode::handle_fd_ready()`] once the command finishes.
+    pub fn grep(
+        &mut self,
+        buffer: &TextBuffer,
+        current_file: &Path,
+    ) -> orfail::Result<Option<Highlight>> {
+        // A newer query supersedes the running search
+        self.cancel_search();
+
         if self.query.is_empty() {
-            return Ok(Highlight::default());
+            self.project_hits.clear();
+            self.project_hit_index = None;
+            return Ok(Some(Highlight::default()));
         }
 
-        let buffer = buffer.to_single_text();
-        let output = self.execute_command(&buffer).or_fail()?;
-        let dir = std::env::var_os("HOME") // TODO
-            .map(PathBuf::from)
-            .unwrap_or_default();
-        std::fs::write(dir.join(".kk.highlight"), &output).or_fail()?;
+        let (sub_buffer, origin) = match &self.action.scope {
+            GrepScope::Buffer => (None, None),
+            GrepScope::Mark => match self.mark_region {
+                // Search only the marked text, then map the hits back to buffer positions
+                Some((start, end)) => (Some(buffer.sub_buffer(start, end)), Some(start)),
+                None => (None, None),
+            },
+            GrepScope::Project { dir } => {
+                let job = GrepJob::Project {
+                    current_file: current_file.to_path_buf(),
+                    cwd: None,
+                };
+                let process = self.spawn_command(None, Some(dir), job, current_file);
+                self.process = Some(process.or_fail()?);
+                return Ok(None);
+            }
+        };
+        let target = sub_buffer.as_ref().unwrap_or(buffer);
 
-        Highlight::parse(&output, &buffer).or_fail()
-    }
+        if let GrepEngine::Builtin { .. } = self.action.engine {
+            let regex = self.query_regex().or_fail()?;
+            let mut highlight = Highlight::search(&regex, target);
+            if let Some(origin) = origin {
+                highlight.offset(origin);
+            }
+            return Ok(Some(highlight));
+        }
 
-    pub fn next_query(&mut self) -> orfail::Result<Option<String>> {
-        // TODO: optimize
-        let Some(i) = self.query_history_index.and_then(|i| i.checked_sub(1)) else {
-            self.query_history_index = None;
-            return Ok(None);
+        let input = target.to_single_text();
+        let job = GrepJob::Text {
+            input: input.clone(),
+            origin,
         };
+        let process = self.spawn_command(Some(&input), None, job, current_file);
+        self.process = Some(process.or_fail()?);
+        Ok(None)
+    }
 
-        let path = self.query_history_path();
-        let text = std::fs::read_to_string(&path).or_fail()?;
-        let Some(query) = text.lines().nth_back(i) else {
-            self.query_history_index = None;
-            return Ok(None);
-        };
-        self.query_history_index = Some(i);
-        Ok(Some(query.to_owned()))
+    pub fn is_searching(&self) -> bool {
+        self.process.is_some()
     }
 
-    pub fn prev_query(&mut self) -> orfail::Result<Option<String>> {
-        // TODO: optimize
-        let i = self.query_history_index.map(|i| i + 1).unwrap_or(0);
+    /// Returns the file descriptors to poll while an external grep command is running.
+    pub fn pending_fds(&self) -> Vec<RawFd> {
+        self.process.as_ref().map(|p| p.fds()).unwrap_or_default()
+    }
 
-        let path = self.query_history_path();
-        let text = std::fs::read_to_string(&path).or_fail()?;
-        let Some(query) = text.lines().nth_back(i) else {
-            self.query_history_index = None;
-            return Ok(None);
-        };
-        self.query_history_index = Some(i);
-        Ok(Some(query.to_owned()))
+    /// Returns the file descriptor to poll for writing while the grep input is being written.
+    pub fn pending_write_fd(&self) -> Option<RawFd> {
+        self.process.as_ref().and_then(|p| p.write_fd())
     }
 
-    pub fn save_query(&self) -> orfail::Result<()> {
-        let path = self.query_history_path();
-        let mut file = std::fs::OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(path)
-            .or_fail()?;
-        writeln!(file, "{}", self.query.iter().collect::<String>()).or_fail()?;
-        Ok(())
+    /// Kills the running grep command, if any.
+    pub fn cancel_search(&mut self) -> bool {
+        self.process.take().is_some()
     }
 
-    fn query_history_path(&self) -> PathBuf {
+    /// Reads the output of the running grep command and returns the result once it finishes.
+    pub fn handle_fd_ready(
+        &mut self,
+        fd: RawFd,
+        buffer: &TextBuffer,
+    ) -> orfail::Result<Option<Highlight>> {
+        let Some(process) = &mut self.process else {
+            return Ok(None);
+        };
+        if process.write_fd() == Some(fd) {
+            process.write_ready().or_fail()?;
+            return Ok(None);
+        }
+        if !process.read_ready(fd).or_fail()? {
+            return Ok(None);
+        }
+
+        let mut process = self.process.take().expect("infallible");
+        let output = process.finish().or_fail()?;
         let dir = std::env::var_os("HOME") // TODO
             .map(PathBuf::from)
             .unwrap_or_default();
-        dir.join(".kk.grep-queries")
+        match &process.job {
+            GrepJob::Text { input, origin } => {
+                std::fs::write(dir.join(".kk.highlight"), &output).or_fail()?;
+                let mut highlight = Highlight::parse(&output, input).or_fail()?;
+                if let Some(origin) = origin {
+                    highlight.offset(*origin);
+                }
+                Ok(Some(highlight))
+            }
+            GrepJob::Project { current_file, cwd } => {
+                self.project_hits = output
+                    .lines()
+                    .filter_map(|line| ProjectHit::parse(line, current_file, cwd.as_deref()))
+                    .collect();
+                self.project_hit_index = None;
+
+                // The results buffer, opened by "grep-results-open"
+                let mut results = String::new();
+                for hit in &self.project_hits {
+                    let _ = writeln!(results, "{}: {}", hit.anchor.to_absolute(), hit.text);
+                }
+                std::fs::write(results_file_path(), results).or_fail()?;
+                Ok(Some(self.highlight_in(buffer)))
+            }
+        }
+    }
+
+    /// Highlights the query in `buffer` without running the grep command.
+    ///
+    /// This is used to show the hits in a file opened from the project search results.
+    pub fn highlight_in(&self, buffer: &TextBuffer) -> Highlight {
+        match self.query_regex() {
+            Ok(regex) if !self.query.is_empty() => Highlight::search(&regex, buffer),
+            _ => Highlight::default(),
+        }
+    }
+
+    /// Returns a preview of the project search results around the current hit.
+    pub fn project_hits_preview(&self, max_rows: usize) -> Option<TextPreview> {
+        if self.project_hits.is_empty() || max_rows == 0 {
+            return None;
+        }
+
+        let current = self.project_hit_index.unwrap_or(0);
+        let start = current
+            .saturating_sub(max_rows / 2)
+            .min(self.project_hits.len().saturating_sub(max_rows));
+        let mut text = String::new();
+        for (i, hit) in self
+            .project_hits
+            .iter()
+            .enumerate()
+            .skip(start)
+            .take(max_rows)
+        {
+            let marker = if Some(i) == self.project_hit_index {
+                '>'
+            } else {
+                ' '
+            };
+            let _ = writeln!(text, "{marker} {}", hit.line);
+        }
+
+        let title = match self.project_hit_index {
+            Some(i) => format!("grep results ({}/{})", i + 1, self.project_hits.len()),
+            None => format!("grep results ({})", self.project_hits.len()),
+        };
+        Some(TextPreview::new(
+            Some(TextPreviewPane::new(&title, &text)),
+            None,
+        ))
+    }
+
+    pub fn next_query(&mut self) -> orfail::Result<Option<String>> {
+        self.query_history.next(&self.query.text).or_fail()
+    }
+
+    pub fn prev_query(&mut self) -> orfail::Result<Option<String>> {
+        self.query_history.prev(&self.query.text).or_fail()
+    }
+
+    pub fn save_query(&self) -> orfail::Result<()> {
+        self.query_history.save(&self.query.text).or_fail()
     }
 
-    fn execute_command(&self, buffer: &str) -> orfail::Result<String> {
+    fn spawn_command(
+        &self,
+        input: Option<&str>,
+        dir: Option<&Path>,
+        mut job: GrepJob,
+        current_file: &Path,
+    ) -> orfail::Result<GrepProcess> {
         let mut cmd = std::process::Command::new(&self.action.command);
+        let cwd = apply_command_env(&mut cmd, &self.action.env, current_file);
+        if let GrepJob::Project { cwd: job_cwd, .. } = &mut job {
+            *job_cwd = cwd; // The command reports paths relative to its working directory
+        }
         for arg in &self.action.args {
             cmd.arg(arg);
         }
-        cmd.arg(self.query.iter().copied().collect::<String>());
+        cmd.arg(self.query.to_text());
+        if let Some(dir) = dir {
+            cmd.arg(dir);
+        }
 
-        cmd.stdin(std::process::Stdio::piped());
+        if input.is_some() {
+            cmd.stdin(std::process::Stdio::piped());
+        } else {
+            cmd.stdin(std::process::Stdio::null());
+        }
         cmd.stdout(std::process::Stdio::piped());
         cmd.stderr(std::process::Stdio::piped());
 
         let mut child = cmd
             .spawn()
             .or_fail_with(|e| format!("Failed to execute grep command: {e}"))?;
+        let stdout = child.stdout.take().or_fail()?;
+        let stderr = child.stderr.take().or_fail()?;
+        tuinix::set_nonblocking(stdout.as_raw_fd()).or_fail()?;
+        tuinix::set_nonblocking(stderr.as_raw_fd()).or_fail()?;
+
+        // The input is written as the pipe becomes writable so that a large buffer never blocks
+        let stdin = child.stdin.take();
+        if let Some(stdin) = &stdin {
+            tuinix::set_nonblocking(stdin.as_raw_fd()).or_fail()?;
+        }
 
-        if let Some(mut stdin) = child.stdin.take() {
-            write!(stdin, "{buffer}").or_fail()?;
-            stdin.flush().or_fail()?;
+        Ok(GrepProcess {
+            child,
+            stdin,
+            stdin_buf: input.unwrap_or_default().as_bytes().to_vec(),
+            stdin_written: 0,
+            stdout: Some(stdout),
+            stderr: Some(stderr),
+            stdout_buf: Vec::new(),
+            stderr_buf: Vec::new(),
+            job,
+        })
+    }
+}
+
+#[derive(Debug)]
+enum GrepJob {
+    Text {
+        input: String,
+        origin: Option<TextPosition>, // Buffer position of `input`'s beginning
+    },
+    Project {
+        current_file: PathBuf,
+        cwd: Option<PathBuf>,
+    },
+}
+
+/// An external grep command whose output is read as it becomes available.
+///
+/// The command is killed when this is dropped before finishing.
+#[derive(Debug)]
+struct GrepProcess {
+    child: std::process::Child,
+    stdin: Option<std::process::ChildStdin>,
+    stdin_buf: Vec<u8>,
+    stdin_written: usize, // Number of bytes of `stdin_buf` already written
+    stdout: Option<std::process::ChildStdout>,
+    stderr: Option<std::process::ChildStderr>,
+    stdout_buf: Vec<u8>,
+    stderr_buf: Vec<u8>,
+    job: GrepJob,
+}
+
+impl GrepProcess {
+    fn fds(&self) -> Vec<RawFd> {
+        let stdout = self.stdout.as_ref().map(|x| x.as_raw_fd());
+        let stderr = self.stderr.as_ref().map(|x| x.as_raw_fd());
+        stdout.into_iter().chain(stderr).collect()
+    }
+
+    fn write_fd(&self) -> Option<RawFd> {
+        self.stdin.as_ref().map(|x| x.as_raw_fd())
+    }
+
+    /// Writes as much of the remaining input as possible, closing stdin once all is written.
+    fn write_ready(&mut self) -> orfail::Result<()> {
+        let Some(stdin) = &mut self.stdin else {
+            return Ok(());
+        };
+        while self.stdin_written < self.stdin_buf.len() {
+            match tuinix::try_nonblocking(std::io::Write::write(
+                stdin,
+                &self.stdin_buf[self.stdin_written..],
+            )) {
+                Ok(Some(n)) => self.stdin_written += n,
+                Ok(None) => return Ok(()),
+                // The command may exit without reading all of its input
+                Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => break,
+                Err(e) => return Err(e).or_fail(),
+            }
         }
+        self.stdin = None;
+        Ok(())
+    }
 
-        let output = child
-            .wait_with_output()
-            .or_fail_with(|e| format!("Failed to wait for command: {e}"))?;
+    /// Reads from `fd` and returns `true` once both stdout and stderr are closed.
+    fn read_ready(&mut self, fd: RawFd) -> orfail::Result<bool> {
+        if self.stdout.as_ref().is_some_and(|x| x.as_raw_fd() == fd)
+            && read_available(
+                self.stdout.as_mut().expect("infallible"),
+                &mut self.stdout_buf,
+            )
+            .or_fail()?
+        {
+            self.stdout = None;
+        }
+        if self.stderr.as_ref().is_some_and(|x| x.as_raw_fd() == fd)
+            && read_available(
+                self.stderr.as_mut().expect("infallible"),
+                &mut self.stderr_buf,
+            )
+            .or_fail()?
+        {
+            self.stderr = None;
+        }
+        Ok(self.stdout.is_none() && self.stderr.is_none())
+    }
 
-        match output.status.code() {
+    fn finish(&mut self) -> orfail::Result<String> {
+        let status = self
+            .child
+            .wait()
+            .or_fail_with(|e| format!("Failed to wait for command: {e}"))?;
+        match status.code() {
             Some(0 | 1) => {}
             _ => {
-                let stderr = String::from_utf8_lossy(&output.stderr);
+                let stderr = String::from_utf8_lossy(&self.stderr_buf);
                 return Err(orfail::Failure::new(format!(
                     "Grep command failed: {}",
                     stderr.trim()
                 )));
             }
         }
-        String::from_utf8(output.stdout).or_fail()
+        String::from_utf8(std::mem::take(&mut self.stdout_buf)).or_fail()
+    }
+}
+
+impl Drop for GrepProcess {
+    fn drop(&mut self) {
+        if matches!(self.child.try_wait(), Ok(None)) {
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+        }
+    }
+}
+
+// Reads all currently available bytes and returns `true` on EOF
+fn read_available<R: Read>(reader: &mut R, buf: &mut Vec<u8>) -> orfail::Result<bool> {
+    let mut chunk = [0; 4096];
+    loop {
+        match tuinix::try_nonblocking(reader.read(&mut chunk)).or_fail()? {
+            Some(0) => return Ok(true),
+            Some(n) => buf.extend_from_slice(&chunk[..n]),
+            None => return Ok(false),
+        }
     }
 }
 
+/// A location reported by a project-wide grep command.
+#[derive(Debug, Clone)]
+pub struct ProjectHit {
+    pub anchor: CursorAnchor,
+    pub line: String, // Raw output line, e.g., `path:line:col:text`
+    pub text: String, // Matched line without the location
+}
+
+impl ProjectHit {
+    fn parse(line: &str, current_file: &Path, cwd: Option<&Path>) -> Option<Self> {
+        let anchor = CursorAnchor::parse_for_goto(line, current_file)?;
+        let rest = line
+            .strip_prefix(&format!("{}:{}:", anchor.path.display(), anchor.line))
+            .unwrap_or(line);
+        let text = rest
+            .strip_prefix(&format!("{}:", anchor.char))
+            .unwrap_or(rest);
+        Some(Self {
+            anchor: match cwd {
+                Some(cwd) => anchor.in_dir(cwd),
+                None => anchor,
+            },
+            line: line.to_owned(),
+            text: text.to_owned(),
+        })
+    }
+}
+
+/// Returns the path of the file listing the hits of the last project search.
+pub fn results_file_path() -> PathBuf {
+    std::env::var_os("HOME") // TODO
+        .map(PathBuf::from)
+        .unwrap_or_default()
+        .join(".kk.grep-results")
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct HighlightItem {
     pub start_position: TextPosition,
@@ -182,6 +600,35 @@ impl Highlight {
         Ok(Self { items })
     }
 
+    fn search(regex: &Regex, buffer: &TextBuffer) -> Self {
+        let mut items = Vec::new();
+        for (row, line) in buffer.text.iter().enumerate() {
+            let text = line.0.iter().collect::<String>();
+            for m in regex.find_iter(&text).filter(|m| !m.is_empty()) {
+                let start = text[..m.start()].chars().count();
+                let end = start + m.as_str().chars().count();
+                items.push(HighlightItem {
+                    start_position: TextPosition {
+                        row,
+                        col: line.col_at_char_index(start),
+                    },
+                    end_position: TextPosition {
+                        row,
+                        col: line.col_at_char_index(end),
+                    },
+                });
+            }
+        }
+        Self { items }
+    }
+
+    fn offset(&mut self, origin: TextPosition) {
+        for item in &mut self.items {
+            item.start_position = offset_position(item.start_position, origin);
+            item.end_position = offset_position(item.end_position, origin);
+        }
+    }
+
     pub fn contains(&self, pos: TextPosition) -> bool {
         self.items
             .iter()
@@ -189,6 +636,86 @@ impl Highlight {
     }
 }
 
+/// Expands `\0`..`\9` in `template` to the corresponding capture groups of a match.
+///
+/// `\\` is expanded to a single backslash, and any other character is kept as-is.
+pub fn expand_replacement(template: &str, captures: &regex::Captures) -> String {
+    let mut expanded = String::new();
+    let mut chars = template.chars();
+    while let Some(ch) = chars.next() {
+        if ch != '\\' {
+            expanded.push(ch);
+            continue;
+        }
+        match chars.next() {
+            Some(d @ '0'..='9') => {
+                let i = d as usize - '0' as usize;
+                if let Some(m) = captures.get(i) {
+                    expanded.push_str(m.as_str());
+                }
+            }
+            Some('\\') => expanded.push('\\'),
+            Some(other) => {
+                expanded.push('\\');
+                expanded.push(other);
+            }
+            None => expanded.push('\\'),
+        }
+    }
+    expanded
+}
+
+/// Translates a POSIX basic regular expression into the syntax of [`Regex`].
+///
+/// In basic syntax, `(`, `)`, `{`, `}`, `|`, `+` and `?` are special only when escaped.
+fn basic_to_extended(pattern: &str) -> String {
+    let mut translated = String::with_capacity(pattern.len());
+    let mut chars = pattern.chars();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '\\' => match chars.next() {
+                Some(c @ ('(' | ')' | '{' | '}' | '|' | '+' | '?')) => translated.push(c),
+                Some(c) => {
+                    translated.push('\\');
+                    translated.push(c);
+                }
+                None => translated.push_str("\\\\"),
+            },
+            '(' | ')' | '{' | '}' | '|' | '+' | '?' => {
+                translated.push('\\');
+                translated.push(ch);
+            }
+            _ => translated.push(ch),
+        }
+    }
+    translated
+}
+
+/// Returns whether `template` refers to a capture group with `\N`.
+pub fn has_capture_reference(template: &str) -> bool {
+    let mut chars = template.chars();
+    while let Some(ch) = chars.next() {
+        if ch == '\\' && chars.next().is_some_and(|c| c.is_ascii_digit()) {
+            return true;
+        }
+    }
+    false
+}
+
+fn offset_position(pos: TextPosition, origin: TextPosition) -> TextPosition {
+    if pos.row == 0 {
+        TextPosition {
+            row: origin.row,
+            col: origin.col + pos.col,
+        }
+    } else {
+        TextPosition {
+            row: origin.row + pos.row,
+            col: pos.col,
+        }
+    }
+}
+
 fn byte_offset_to_text_position(text: &str, offset: usize) -> orfail::Result<TextPosition> {
     if offset > text.len() {
         return Err(orfail::Failure::new("Byte offset exceeds text length"));
@@ -225,14 +752,71 @@ impl GrepQueryRenderer {
             unreachable!();
         };
 
-        write!(frame, "$ {} ", grep.action.command).or_fail()?;
-        for arg in &grep.action.args {
-            write!(frame, "{arg} ").or_fail()?;
+        let mut line = grep.prompt();
+        line.extend(&grep.query.text);
+        if let GrepScope::Project { dir } = &grep.action.scope {
+            write!(line, " {}", dir.display()).or_fail()?;
         }
-        for ch in &grep.query {
-            write!(frame, "{ch}").or_fail()?;
+        write!(frame, "{line}").or_fail()?;
+
+        // Show "hit N/M" at the right end of the line
+        if !grep.query.is_empty()
+            && let Some((current, total)) = state.grep_hit_counter()
+        {
+            let counter = match current {
+                _ if grep.is_searching() => "searching...".to_owned(),
+                Some(i) => format!("hit {i}/{total}"),
+                None => format!("hit -/{total}"),
+            };
+            let padding = frame
+                .size()
+                .cols
+                .checked_sub(str_cols(&line) + str_cols(&counter));
+            if let Some(padding) = padding.filter(|&n| n > 0) {
+                write!(frame, "{:padding$}{counter}", "").or_fail()?;
+            }
         }
         writeln!(frame).or_fail()?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expand(pattern: &str, template: &str, text: &str) -> String {
+        let regex = Regex::new(pattern).expect("valid regex");
+        let captures = regex.captures(text).expect("match");
+        expand_replacement(template, &captures)
+    }
+
+    #[test]
+    fn expand_capture_groups() {
+        assert_eq!(expand("(\\w+)=(\\w+)", "\\2=\\1", "key=value"), "value=key");
+        assert_eq!(expand("a(b)?c", "[\\0|\\1]", "ac"), "[ac|]");
+        assert_eq!(expand("a", "\\9", "a"), "");
+    }
+
+    #[test]
+    fn expand_escapes() {
+        assert_eq!(expand("a", "\\\\1", "a"), "\\1");
+        assert_eq!(expand("a", "\\n\\", "a"), "\\n\\");
+        assert_eq!(expand("a", "plain", "a"), "plain");
+    }
+
+    #[test]
+    fn capture_references() {
+        assert!(has_capture_reference("x\\1"));
+        assert!(!has_capture_reference("x\\\\1"));
+        assert!(!has_capture_reference("C:\\path"));
+    }
+
+    #[test]
+    fn translate_basic_syntax() {
+        assert_eq!(basic_to_extended("a\\(b\\)\\{2\\}"), "a(b){2}");
+        assert_eq!(basic_to_extended("f(x) + y?"), "f\\(x\\) \\+ y\\?");
+        assert_eq!(basic_to_extended("a\\|b.*\\."), "a|b.*\\.");
+        assert_eq!(basic_to_extended("end\\"), "end\\\\");
+    }
+}
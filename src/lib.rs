@@ -3,8 +3,18 @@ pub mod anchor;
 pub mod app;
 pub mod buffer;
 pub mod clipboard;
+pub mod command;
+pub mod diff;
+pub mod error_list;
 pub mod grep_mode;
+pub mod line_editor;
+pub mod line_history;
 pub mod message_line;
+pub mod output_pane;
+pub mod preview;
+pub mod prompt_mode;
+pub mod pty;
+pub mod remote;
 pub mod state;
 pub mod status_line;
 pub mod text_area;
@@ -0,0 +1,239 @@
+/// Single-line text field used by prompts such as the grep query.
+///
+/// Editing methods return `true` if the text has changed.
+#[derive(Debug, Default, Clone)]
+pub struct LineEditor {
+    pub text: Vec<char>,
+    pub cursor: usize, // Char index
+}
+
+impl LineEditor {
+    pub fn is_empty(&self) -> bool {
+        self.text.is_empty()
+    }
+
+    pub fn to_text(&self) -> String {
+        self.text.iter().collect()
+    }
+
+    /// Replaces the whole text and moves the cursor to the end.
+    pub fn set_text(&mut self, text: &str) {
+        self.text = text.chars().collect();
+        self.cursor = self.text.len();
+    }
+
+    pub fn insert_char(&mut self, ch: char) -> bool {
+        if ch.is_control() {
+            return false;
+        }
+        self.text.insert(self.cursor, ch);
+        self.cursor += 1;
+        true
+    }
+
+    /// Inserts `s` at the cursor.
+    ///
+    /// As the text is a single line, only the first line of `s` is inserted.
+    /// Tabs are inserted as `\t`, and other control characters are skipped.
+    pub fn insert_str(&mut self, s: &str) -> bool {
+        let old_len = self.text.len();
+        let line = s.lines().next().unwrap_or_default();
+        for ch in line.chars() {
+            if ch == '\t' {
+                self.text.insert(self.cursor, '\\');
+                self.text.insert(self.cursor + 1, 't');
+                self.cursor += 2;
+            } else {
+                self.insert_char(ch);
+            }
+        }
+        self.text.len() != old_len
+    }
+
+    pub fn delete_backward(&mut self) -> bool {
+        if self.cursor == 0 {
+            return false;
+        }
+        self.cursor -= 1;
+        self.text.remove(self.cursor);
+        true
+    }
+
+    pub fn delete_forward(&mut self) -> bool {
+        if self.cursor == self.text.len() {
+            return false;
+        }
+        self.text.remove(self.cursor);
+        true
+    }
+
+    pub fn delete_word_backward(&mut self) -> bool {
+        let start = self.word_start();
+        self.text.drain(start..self.cursor);
+        let changed = start != self.cursor;
+        self.cursor = start;
+        changed
+    }
+
+    pub fn delete_word_forward(&mut self) -> bool {
+        let end = self.word_end();
+        self.text.drain(self.cursor..end);
+        end != self.cursor
+    }
+
+    /// Deletes the text after the cursor.
+    pub fn kill(&mut self) -> bool {
+        if self.cursor == self.text.len() {
+            return false;
+        }
+        self.text.truncate(self.cursor);
+        true
+    }
+
+    /// Swaps the characters around the cursor, like Emacs's `C-t`.
+    pub fn transpose(&mut self) -> bool {
+        if self.text.len() < 2 || self.cursor == 0 {
+            return false;
+        }
+        // At the end of the text, the last two characters are swapped
+        let i = self.cursor.min(self.text.len() - 1);
+        self.text.swap(i - 1, i);
+        self.cursor = i + 1;
+        true
+    }
+
+    pub fn move_left(&mut self) {
+        self.cursor = self.cursor.saturating_sub(1);
+    }
+
+    pub fn move_right(&mut self) {
+        self.cursor = (self.cursor + 1).min(self.text.len());
+    }
+
+    pub fn move_start(&mut self) {
+        self.cursor = 0;
+    }
+
+    pub fn move_end(&mut self) {
+        self.cursor = self.text.len();
+    }
+
+    pub fn move_word_left(&mut self) {
+        self.cursor = self.word_start();
+    }
+
+    pub fn move_word_right(&mut self) {
+        self.cursor = self.word_end();
+    }
+
+    // Start of the word before the cursor (skipping non-word chars first)
+    fn word_start(&self) -> usize {
+        let mut i = self.cursor;
+        while i > 0 && !is_word_char(self.text[i - 1]) {
+            i -= 1;
+        }
+        while i > 0 && is_word_char(self.text[i - 1]) {
+            i -= 1;
+        }
+        i
+    }
+
+    // End of the word after the cursor (skipping non-word chars first)
+    fn word_end(&self) -> usize {
+        let mut i = self.cursor;
+        while i < self.text.len() && !is_word_char(self.text[i]) {
+            i += 1;
+        }
+        while i < self.text.len() && is_word_char(self.text[i]) {
+            i += 1;
+        }
+        i
+    }
+}
+
+pub fn is_word_char(ch: char) -> bool {
+    ch.is_alphanumeric() || ch == '_'
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Text with `|` marking the cursor
+    fn editor(s: &str) -> LineEditor {
+        let cursor = s.find('|').expect("cursor");
+        LineEditor {
+            text: s.replacen('|', "", 1).chars().collect(),
+            cursor: s[..cursor].chars().count(),
+        }
+    }
+
+    fn show(editor: &LineEditor) -> String {
+        let mut text = editor.text.clone();
+        text.insert(editor.cursor, '|');
+        text.into_iter().collect()
+    }
+
+    #[test]
+    fn word_motion() {
+        let left: fn(&mut LineEditor) = LineEditor::move_word_left;
+        let right: fn(&mut LineEditor) = LineEditor::move_word_right;
+        let cases = [
+            ("foo_bar baz|", left, "foo_bar |baz"),
+            ("foo_bar |baz", left, "|foo_bar baz"),
+            ("foo.(bar)|", left, "foo.(|bar)"),
+            ("|foo bar", right, "foo| bar"),
+            ("foo| bar", right, "foo bar|"),
+            ("いろ はに|", left, "いろ |はに"),
+        ];
+        for (before, motion, after) in cases {
+            let mut e = editor(before);
+            motion(&mut e);
+            assert_eq!(show(&e), after, "{before:?}");
+        }
+    }
+
+    #[test]
+    fn word_deletion() {
+        let mut e = editor("foo bar.baz|");
+        assert!(e.delete_word_backward());
+        assert_eq!(show(&e), "foo bar.|");
+        assert!(e.delete_word_backward());
+        assert_eq!(show(&e), "foo |");
+
+        let mut e = editor("|foo bar");
+        assert!(e.delete_word_forward());
+        assert_eq!(show(&e), "| bar");
+        assert!(e.delete_word_forward());
+        assert_eq!(show(&e), "|");
+        assert!(!e.delete_word_forward());
+        assert!(!e.delete_word_backward());
+    }
+
+    #[test]
+    fn insert_first_line_only() {
+        let mut e = editor("a|b");
+        assert!(e.insert_str("x\ty\nsecond line"));
+        assert_eq!(show(&e), "ax\\ty|b");
+
+        let mut e = editor("a|b");
+        assert!(!e.insert_str("\nsecond line"));
+        assert!(!e.insert_str(""));
+        assert!(e.insert_str("c\u{7}d\r\n"));
+        assert_eq!(show(&e), "acd|b");
+    }
+
+    #[test]
+    fn kill_and_transpose() {
+        let mut e = editor("ab|cd");
+        assert!(e.kill());
+        assert_eq!(show(&e), "ab|");
+        assert!(!e.kill());
+        assert!(e.transpose());
+        assert_eq!(show(&e), "ba|");
+
+        let mut e = editor("a|bc");
+        assert!(e.transpose());
+        assert_eq!(show(&e), "ba|c");
+    }
+}
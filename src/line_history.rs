@@ -0,0 +1,258 @@
+use std::path::{Path, PathBuf};
+
+use orfail::OrFail;
+
+const MAX_HISTORY_SIZE: usize = 1000;
+
+/// Input history of a prompt such as the grep query, most recent last.
+///
+/// Browsing the history only visits queries starting with the text typed before browsing,
+/// like shell history search.
+#[derive(Debug)]
+pub struct LineHistory {
+    pub path: PathBuf,
+    entries: Option<Vec<String>>, // Loaded on first use
+    cursor: Option<LineHistoryCursor>,
+}
+
+#[derive(Debug)]
+struct LineHistoryCursor {
+    index: usize,
+    prefix: String,
+    recalled: String,
+}
+
+impl LineHistory {
+    /// Makes a history stored in `~/{base_name}`, or `~/{base_name}.{name}` for a named history.
+    pub fn new(base_name: &str, name: Option<&str>) -> Self {
+        let dir = std::env::var_os("HOME") // TODO
+            .map(PathBuf::from)
+            .unwrap_or_default();
+        let file_name = match name {
+            None => base_name.to_owned(),
+            Some(name) => {
+                let name = name
+                    .trim_matches(|c: char| !c.is_alphanumeric())
+                    .chars()
+                    .map(|c| {
+                        if c.is_alphanumeric() || c == '-' {
+                            c
+                        } else {
+                            '_'
+                        }
+                    })
+                    .collect::<String>();
+                format!("{base_name}.{name}")
+            }
+        };
+        Self {
+            path: dir.join(file_name),
+            entries: None,
+            cursor: None,
+        }
+    }
+
+    fn load(path: &Path) -> orfail::Result<Vec<String>> {
+        match std::fs::read_to_string(path) {
+            Ok(text) => Ok(text.lines().map(|line| line.to_owned()).collect()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(orfail::Failure::new(format!(
+                "Failed to read {}: {e}",
+                path.display()
+            ))),
+        }
+    }
+
+    pub fn prev(&mut self, query: &[char]) -> orfail::Result<Option<String>> {
+        let query = query.iter().collect::<String>();
+        if self.entries.is_none() {
+            self.entries = Some(Self::load(&self.path).or_fail()?);
+        }
+        let entries = self.entries.as_ref().expect("infallible");
+
+        // Editing the recalled query starts a new search with the edited text as the prefix
+        let (end, prefix) = match self.cursor.take() {
+            Some(cursor) if cursor.recalled == query => (cursor.index, cursor.prefix),
+            _ => (entries.len(), query.clone()),
+        };
+        let Some(index) = entries[..end]
+            .iter()
+            .rposition(|entry| entry.starts_with(&prefix) && *entry != query)
+        else {
+            if end < entries.len() {
+                // Stay at the oldest match
+                self.cursor = Some(LineHistoryCursor {
+                    index: end,
+                    prefix,
+                    recalled: query,
+                });
+            }
+            return Ok(None);
+        };
+        let recalled = entries[index].clone();
+        self.cursor = Some(LineHistoryCursor {
+            index,
+            prefix,
+            recalled: recalled.clone(),
+        });
+        Ok(Some(recalled))
+    }
+
+    pub fn next(&mut self, query: &[char]) -> orfail::Result<Option<String>> {
+        let query = query.iter().collect::<String>();
+        let Some(cursor) = self.cursor.take() else {
+            return Ok(None);
+        };
+        if cursor.recalled != query {
+            return Ok(None);
+        }
+        let entries = self.entries.as_deref().unwrap_or_default();
+        let Some(offset) = entries[cursor.index + 1..]
+            .iter()
+            .position(|entry| entry.starts_with(&cursor.prefix) && *entry != query)
+        else {
+            // Moving past the newest match restores the typed text
+            return Ok(Some(cursor.prefix));
+        };
+        let index = cursor.index + 1 + offset;
+        let recalled = entries[index].clone();
+        self.cursor = Some(LineHistoryCursor {
+            index,
+            prefix: cursor.prefix,
+            recalled: recalled.clone(),
+        });
+        Ok(Some(recalled))
+    }
+
+    pub fn save(&self, query: &[char]) -> orfail::Result<()> {
+        let query = query.iter().collect::<String>();
+        if query.is_empty() || query.contains('\n') {
+            return Ok(());
+        }
+
+        // Re-read the file so that queries saved by other sessions are preserved
+        let mut entries = Self::load(&self.path).or_fail()?;
+        entries.retain(|entry| *entry != query);
+        entries.push(query);
+        let excess = entries.len().saturating_sub(MAX_HISTORY_SIZE);
+        entries.drain(..excess);
+
+        let mut text = String::new();
+        for entry in &entries {
+            text.push_str(entry);
+            text.push('\n');
+        }
+        std::fs::write(&self.path, text)
+            .or_fail_with(|e| format!("Failed to write {}: {e}", self.path.display()))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chars(s: &str) -> Vec<char> {
+        s.chars().collect()
+    }
+
+    fn history(test_name: &str, entries: &[&str]) -> LineHistory {
+        let path = std::env::temp_dir().join(format!(
+            "kk-line-history-{test_name}-{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let history = LineHistory {
+            path,
+            entries: None,
+            cursor: None,
+        };
+        for entry in entries {
+            history.save(&chars(entry)).expect("save");
+        }
+        history
+    }
+
+    fn read(history: &LineHistory) -> String {
+        let text = std::fs::read_to_string(&history.path).expect("read");
+        let _ = std::fs::remove_file(&history.path);
+        text
+    }
+
+    #[test]
+    fn save_deduplicates() {
+        let history = history("dedup", &["foo", "bar", "foo", "", "a\nb"]);
+        assert_eq!(read(&history), "bar\nfoo\n");
+    }
+
+    #[test]
+    fn browse_all_entries() {
+        let mut history = history("browse", &["one", "two", "three"]);
+        assert_eq!(history.prev(&[]).expect("prev").as_deref(), Some("three"));
+        assert_eq!(
+            history.prev(&chars("three")).expect("prev").as_deref(),
+            Some("two")
+        );
+        assert_eq!(
+            history.prev(&chars("two")).expect("prev").as_deref(),
+            Some("one")
+        );
+        assert_eq!(history.prev(&chars("one")).expect("prev"), None);
+        assert_eq!(
+            history.next(&chars("one")).expect("next").as_deref(),
+            Some("two")
+        );
+        assert_eq!(
+            history.next(&chars("two")).expect("next").as_deref(),
+            Some("three")
+        );
+        assert_eq!(
+            history.next(&chars("three")).expect("next").as_deref(),
+            Some("")
+        );
+        assert_eq!(history.next(&[]).expect("next"), None);
+        read(&history);
+    }
+
+    #[test]
+    fn browse_by_prefix() {
+        let mut history = history("prefix", &["fn main", "struct", "fn new", "fn"]);
+        assert_eq!(
+            history.prev(&chars("fn")).expect("prev").as_deref(),
+            Some("fn new")
+        );
+        assert_eq!(
+            history.prev(&chars("fn new")).expect("prev").as_deref(),
+            Some("fn main")
+        );
+        assert_eq!(history.prev(&chars("fn main")).expect("prev"), None);
+        assert_eq!(
+            history.next(&chars("fn main")).expect("next").as_deref(),
+            Some("fn new")
+        );
+        assert_eq!(
+            history.next(&chars("fn new")).expect("next").as_deref(),
+            Some("fn")
+        );
+        read(&history);
+    }
+
+    #[test]
+    fn editing_restarts_browsing() {
+        let mut history = history("edit", &["fn main", "fn new", "struct"]);
+        assert_eq!(history.prev(&[]).expect("prev").as_deref(), Some("struct"));
+        assert_eq!(
+            history.prev(&chars("fn")).expect("prev").as_deref(),
+            Some("fn new")
+        );
+        assert_eq!(history.next(&chars("fn ne")).expect("next"), None);
+        read(&history);
+    }
+
+    #[test]
+    fn sanitize_history_name() {
+        let history = LineHistory::new(".kk_history", Some("rg -i/x"));
+        let file_name = history.path.file_name().expect("file name");
+        assert_eq!(file_name, ".kk_history.rg_-i_x");
+    }
+}
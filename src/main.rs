@@ -2,7 +2,7 @@ use std::path::PathBuf;
 
 use orfail::OrFail;
 
-use kk::app::App;
+use kk::{app::App, remote::send_actions};
 
 fn main() -> noargs::Result<()> {
     let mut args = noargs::raw_args();
@@ -15,6 +15,44 @@ fn main() -> noargs::Result<()> {
     }
     noargs::HELP_FLAG.take_help(&mut args);
 
+    if noargs::flag("remote")
+        .doc("Send actions to the kk listening on --socket, instead of editing a file")
+        .take(&mut args)
+        .is_present()
+    {
+        let socket: Option<PathBuf> = noargs::opt("socket")
+            .ty("PATH")
+            .env("KK_SOCKET")
+            .doc("Unix domain socket of the kk to send actions to")
+            .take(&mut args)
+            .present_and_then(|a| a.value().parse())?;
+        let action: Option<String> = noargs::arg("[ACTION]")
+            .doc("JSON-encoded action (if omitted, one action per line is read from stdin)")
+            .take(&mut args)
+            .present_and_then(|a| a.value().parse())?;
+        if let Some(help) = args.finish()? {
+            print!("{help}");
+            return Ok(());
+        }
+
+        let socket = socket.or_fail_with(|()| "--socket is required".to_owned())?;
+        let actions = match action {
+            Some(action) => vec![action],
+            None => std::io::stdin()
+                .lines()
+                .collect::<Result<Vec<_>, _>>()
+                .or_fail()?,
+        };
+        send_actions(&socket, &actions).or_fail()?;
+        return Ok(());
+    }
+
+    // Not taken from KK_SOCKET, which names the socket of another kk that `--remote` sends to
+    let socket: Option<PathBuf> = noargs::opt("socket")
+        .ty("PATH")
+        .doc("Unix domain socket to receive actions on (see --remote)")
+        .take(&mut args)
+        .present_and_then(|a| a.value().parse())?;
     let path: PathBuf = noargs::arg("FILE")
         .example("/path/to/file")
         .take(&mut args)
@@ -24,7 +62,7 @@ fn main() -> noargs::Result<()> {
         return Ok(());
     }
 
-    let app = App::new(path).or_fail()?;
+    let app = App::new(path, socket.as_deref()).or_fail()?;
     app.run().or_fail()?;
 
     Ok(())
@@ -0,0 +1,223 @@
+use std::{fmt::Write, os::fd::RawFd, path::PathBuf, process::ExitStatus};
+
+use mame::preview::{TextPreview, TextPreviewPane};
+use orfail::OrFail;
+
+use crate::{action::ErrorPattern, command::exit_summary, error_list::ErrorList, pty::PtyProcess};
+
+/// Maximum number of lines kept in the pane, beyond which the oldest ones are dropped.
+const MAX_LINES: usize = 10_000;
+
+/// Read-only pane showing the output of a command run in a pty, streamed as it arrives.
+#[derive(Debug)]
+pub struct OutputPane {
+    pub name: String, // Command line, for display
+    pub lines: Vec<String>,
+    pub omitted: usize, // Number of the oldest lines dropped to stay within `MAX_LINES`
+    pub status: Option<ExitStatus>, // Set once the command finishes
+    pub dir: Option<PathBuf>, // Working directory of the command, if not kk's
+    partial: Vec<u8>,   // Bytes of the line being printed
+    process: Option<PtyProcess>,
+}
+
+impl OutputPane {
+    pub fn new(name: String, process: PtyProcess, dir: Option<PathBuf>) -> Self {
+        Self {
+            name,
+            lines: Vec::new(),
+            omitted: 0,
+            status: None,
+            dir,
+            partial: Vec::new(),
+            process: Some(process),
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.process.is_some()
+    }
+
+    pub fn pending_fd(&self) -> Option<RawFd> {
+        self.process.as_ref().map(|p| p.fd())
+    }
+
+    /// Reads the output of the command, returning `true` once it finishes.
+    pub fn handle_fd_ready(&mut self, fd: RawFd) -> orfail::Result<bool> {
+        let Some(process) = &mut self.process else {
+            return Ok(false);
+        };
+        if process.fd() != fd {
+            return Ok(false);
+        }
+
+        let eof = process.read_available(&mut self.partial).or_fail()?;
+        while let Some(i) = self.partial.iter().position(|&b| b == b'\n') {
+            let bytes = self.partial.drain(..=i).collect::<Vec<_>>();
+            self.lines.push(clean_line(&bytes[..i]));
+        }
+        self.drop_old_lines();
+        if !eof {
+            return Ok(false);
+        }
+
+        if !self.partial.is_empty() {
+            let bytes = std::mem::take(&mut self.partial);
+            self.lines.push(clean_line(&bytes));
+            self.drop_old_lines();
+        }
+        let mut process = self.process.take().expect("infallible");
+        self.status = Some(process.wait().or_fail()?);
+        Ok(true)
+    }
+
+    // Done once per read rather than per line, as dropping the first lines moves all the others
+    fn drop_old_lines(&mut self) {
+        let excess = self.lines.len().saturating_sub(MAX_LINES);
+        if excess > 0 {
+            self.lines.drain(..excess);
+            self.omitted += excess;
+        }
+    }
+
+    /// Scans the output for error locations, which are relative to the command's directory.
+    pub fn scan_errors(&self, patterns: &[ErrorPattern]) -> ErrorList {
+        let text = self.lines.join("\n");
+        let source = format!("`$ {}`", self.name);
+        let mut errors = ErrorList::scan(source, &text, patterns, self.dir.as_deref());
+        errors.from_output = true;
+        errors
+    }
+
+    pub fn summary(&self) -> String {
+        match self.status {
+            Some(status) => exit_summary(status, &self.name),
+            None => format!("`$ {}` running...", self.name),
+        }
+    }
+
+    /// Returns a preview of the latest output, or of the output around the current error.
+    ///
+    /// `errors` is the error list scanned from this output, if any.
+    pub fn preview(&self, max_rows: usize, errors: Option<&ErrorList>) -> Option<TextPreview> {
+        if max_rows == 0 {
+            return None;
+        }
+
+        let partial = (!self.partial.is_empty()).then(|| clean_line(&self.partial));
+        let lines = self.lines.iter().chain(partial.as_ref());
+        let total = self.lines.len() + usize::from(partial.is_some());
+        let current = errors.and_then(|e| e.current_row());
+        let start = match current {
+            Some(row) => row
+                .saturating_sub(max_rows / 2)
+                .min(total.saturating_sub(max_rows)),
+            None => total.saturating_sub(max_rows),
+        };
+        let mut text = String::new();
+        for (i, line) in lines.enumerate().skip(start).take(max_rows) {
+            let marker = if Some(i) == current { '>' } else { ' ' };
+            let _ = writeln!(text, "{marker} {line}");
+        }
+
+        let mut title = match (errors, errors.and_then(|e| e.index)) {
+            (Some(e), Some(i)) => format!("{} ({}/{})", self.summary(), i + 1, e.errors.len()),
+            (Some(e), None) => format!("{} ({})", self.summary(), e.errors.len()),
+            (None, _) => self.summary(),
+        };
+        if self.omitted > 0 {
+            let _ = write!(title, " [first {} lines omitted]", self.omitted);
+        }
+        Some(TextPreview::new(
+            Some(TextPreviewPane::new(&title, &text)),
+            None,
+        ))
+    }
+}
+
+// Drops escape sequences and keeps only the text after the last carriage return,
+// as a terminal would show it
+fn clean_line(bytes: &[u8]) -> String {
+    let text = String::from_utf8_lossy(bytes);
+    let mut line = String::new();
+    let mut chars = text.chars().peekable();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '\x1b' => match chars.next() {
+                // CSI: parameters and intermediates, then a final byte
+                Some('[') => {
+                    while let Some(c) = chars.next()
+                        && !('\x40'..='\x7e').contains(&c)
+                    {}
+                }
+                // OSC: terminated by BEL or ST
+                Some(']') => {
+                    while let Some(c) = chars.next() {
+                        if c == '\x07' || (c == '\x1b' && chars.next_if_eq(&'\\').is_some()) {
+                            break;
+                        }
+                    }
+                }
+                _ => {}
+            },
+            '\r' if chars.peek().is_some() => line.clear(),
+            '\t' => line.push(ch),
+            _ if ch.is_control() => {}
+            _ => line.push(ch),
+        }
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{process::Command, time::Duration};
+
+    fn run(script: &str) -> OutputPane {
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", script]);
+        let size = tuinix::TerminalSize { rows: 24, cols: 80 };
+        let process = PtyProcess::spawn(cmd, size).expect("spawn");
+        let mut pane = OutputPane::new(script.to_owned(), process, None);
+        for _ in 0..1000 {
+            let fd = pane.pending_fd().expect("running");
+            if pane.handle_fd_ready(fd).expect("read") {
+                return pane;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        panic!("`{script}` did not finish");
+    }
+
+    #[test]
+    fn clean_escape_sequences() {
+        assert_eq!(clean_line(b"\x1b[1;31merror\x1b[0m: x"), "error: x");
+        assert_eq!(clean_line(b"\x1b]0;title\x07text"), "text");
+        assert_eq!(clean_line(b"\x1b]8;;url\x1b\\link\x1b]8;;\x1b\\"), "link");
+    }
+
+    #[test]
+    fn clean_carriage_returns_and_controls() {
+        assert_eq!(clean_line(b" 10%\r 50%\r100%"), "100%");
+        assert_eq!(clean_line(b"done\r"), "done");
+        assert_eq!(clean_line(b"a\tb\x07c"), "a\tbc");
+    }
+
+    #[test]
+    fn stream_lines() {
+        let pane = run("printf 'a\\nb\\n\\033[32mc\\033[0m'; exit 3");
+        assert_eq!(pane.lines, ["a", "b", "c"]);
+        assert_eq!(pane.status.and_then(|s| s.code()), Some(3));
+        assert!(!pane.is_running());
+    }
+
+    #[test]
+    fn cap_lines() {
+        let pane = run(&format!("seq {}", MAX_LINES + 5));
+        assert_eq!(pane.lines.len(), MAX_LINES);
+        assert_eq!(pane.omitted, 5);
+        assert_eq!(pane.lines[0], "6");
+        assert_eq!(pane.lines.last().map(|l| l.as_str()), Some("10005"));
+    }
+}
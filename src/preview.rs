@@ -0,0 +1,157 @@
+use std::fmt::Write;
+
+use mame::{
+    preview::{FilePreviewPaneSpec, FilePreviewSpec, TextPreview, TextPreviewPane},
+    terminal::str_cols,
+};
+use orfail::OrFail;
+
+/// Scrollable preview of files or command output, shown over the bottom of the text area.
+///
+/// The preview cursor selects a line of the first pane, and the second pane scrolls along with it.
+#[derive(Debug)]
+pub struct Preview {
+    pub left_pane: Option<PreviewPane>,
+    pub right_pane: Option<PreviewPane>,
+    pub cursor: usize,
+    scroll: usize, // First visible line
+}
+
+#[derive(Debug)]
+pub struct PreviewPane {
+    pub title: String,
+    pub lines: Vec<String>,
+}
+
+impl PreviewPane {
+    pub fn new(title: &str, text: &str) -> Self {
+        Self {
+            title: title.to_owned(),
+            lines: text
+                .lines()
+                .map(|line| line.replace('\t', "    "))
+                .collect(),
+        }
+    }
+
+    fn load(spec: &FilePreviewPaneSpec) -> orfail::Result<Self> {
+        // A missing file is shown as an empty pane, as the command may not have created it
+        let content = if spec.file.exists() {
+            std::fs::read(&spec.file)
+                .or_fail_with(|e| format!("failed to read file {}: {e}", spec.file.display()))?
+        } else {
+            Vec::new()
+        };
+        let title = spec
+            .file
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or_default();
+        Ok(Self::new(title, &String::from_utf8_lossy(&content)))
+    }
+
+    fn to_text_preview_pane(
+        &self,
+        start: usize,
+        rows: usize,
+        cursor: Option<usize>,
+    ) -> TextPreviewPane {
+        // The first line is padded to the widest line so that the pane keeps its width while scrolling
+        let cols = self
+            .lines
+            .iter()
+            .map(|line| str_cols(line))
+            .max()
+            .unwrap_or_default();
+        let mut text = String::new();
+        for (i, line) in self.lines.iter().enumerate().skip(start).take(rows) {
+            if let Some(cursor) = cursor {
+                let marker = if i == cursor { '>' } else { ' ' };
+                let _ = write!(text, "{marker} ");
+            }
+            let padding = if i == start { cols - str_cols(line) } else { 0 };
+            let _ = writeln!(text, "{line}{:padding$}", "");
+        }
+        TextPreviewPane::new(&self.title, &text)
+    }
+}
+
+impl Preview {
+    pub fn new(left_pane: Option<PreviewPane>, right_pane: Option<PreviewPane>) -> Self {
+        Self {
+            left_pane,
+            right_pane,
+            cursor: 0,
+            scroll: 0,
+        }
+    }
+
+    pub fn load(spec: &FilePreviewSpec) -> orfail::Result<Self> {
+        let left_pane = spec
+            .left_pane
+            .as_ref()
+            .map(PreviewPane::load)
+            .transpose()
+            .or_fail()?;
+        let right_pane = spec
+            .right_pane
+            .as_ref()
+            .map(PreviewPane::load)
+            .transpose()
+            .or_fail()?;
+        Ok(Self::new(left_pane, right_pane))
+    }
+
+    // The cursor belongs to the left pane unless it is missing or empty
+    fn cursor_in_left_pane(&self) -> bool {
+        self.left_pane
+            .as_ref()
+            .is_some_and(|pane| !pane.lines.is_empty())
+    }
+
+    fn cursor_pane(&self) -> Option<&PreviewPane> {
+        if self.cursor_in_left_pane() {
+            self.left_pane.as_ref()
+        } else {
+            self.right_pane.as_ref()
+        }
+    }
+
+    pub fn cursor_line(&self) -> Option<&str> {
+        self.cursor_pane()?
+            .lines
+            .get(self.cursor)
+            .map(String::as_str)
+    }
+
+    pub fn cursor_up(&mut self) {
+        self.cursor = self.cursor.saturating_sub(1);
+    }
+
+    pub fn cursor_down(&mut self) {
+        let rows = self.cursor_pane().map_or(0, |pane| pane.lines.len());
+        self.cursor = (self.cursor + 1).min(rows.saturating_sub(1));
+    }
+
+    /// Returns the visible part of the preview, scrolled so that the cursor is shown.
+    pub fn text_preview(&mut self, max_rows: usize) -> TextPreview {
+        let max_rows = max_rows.max(1);
+        if self.cursor < self.scroll {
+            self.scroll = self.cursor;
+        } else if self.cursor >= self.scroll + max_rows {
+            self.scroll = self.cursor + 1 - max_rows;
+        }
+
+        let in_left = self.cursor_in_left_pane();
+        let pane = |pane: &Option<PreviewPane>, has_cursor: bool| {
+            pane.as_ref().map(|pane| {
+                let cursor = has_cursor.then_some(self.cursor);
+                pane.to_text_preview_pane(self.scroll, max_rows, cursor)
+            })
+        };
+        TextPreview::new(
+            pane(&self.left_pane, in_left),
+            pane(&self.right_pane, !in_left),
+        )
+    }
+}
@@ -0,0 +1,162 @@
+use std::fmt::Write;
+
+use mame::terminal::UnicodeTerminalFrame as TerminalFrame;
+use orfail::OrFail;
+use tuinix::{TerminalPosition, TerminalRegion};
+
+use crate::{
+    action::PromptAction, line_editor::LineEditor, line_history::LineHistory, state::State,
+};
+
+#[derive(Debug)]
+pub struct PromptMode {
+    pub action: PromptAction,
+    pub input: LineEditor,
+    history: LineHistory,
+}
+
+impl PromptMode {
+    pub fn new(action: PromptAction) -> Self {
+        let name = action.history.as_deref().unwrap_or(&action.label);
+        let history = LineHistory::new(".kk.prompt-history", Some(name));
+        Self {
+            action,
+            input: LineEditor::default(),
+            history,
+        }
+    }
+
+    pub fn cursor_position(&self, region: TerminalRegion) -> TerminalPosition {
+        let mut frame = TerminalFrame::new(region.size);
+        let mut pos = region.position;
+
+        let _ = write!(frame, "{} ", self.action.label);
+        for ch in self.input.text.iter().take(self.input.cursor) {
+            let _ = write!(frame, "{ch}");
+        }
+        pos.col = frame.cursor().col;
+
+        pos
+    }
+
+    pub fn next_input(&mut self) -> orfail::Result<Option<String>> {
+        self.history.next(&self.input.text).or_fail()
+    }
+
+    pub fn prev_input(&mut self) -> orfail::Result<Option<String>> {
+        self.history.prev(&self.input.text).or_fail()
+    }
+
+    pub fn save_input(&self) -> orfail::Result<()> {
+        self.history.save(&self.input.text).or_fail()
+    }
+}
+
+#[derive(Debug)]
+pub struct PromptRenderer;
+
+impl PromptRenderer {
+    pub fn render(&self, state: &State, frame: &mut TerminalFrame) -> orfail::Result<()> {
+        let Some(prompt) = &state.prompt else {
+            unreachable!();
+        };
+
+        let input = prompt.input.to_text();
+        writeln!(frame, "{} {input}", prompt.action.label).or_fail()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tuinix::TerminalSize;
+
+    use crate::action::Action;
+
+    fn action(json: &str) -> Action {
+        let json = nojson::RawJson::parse(json).expect("valid json");
+        Action::try_from(json.value()).expect("valid action")
+    }
+
+    fn prompt(json: &str) -> PromptAction {
+        match action(json) {
+            Action::Prompt(action) => action,
+            action => panic!("not a prompt: {action:?}"),
+        }
+    }
+
+    #[test]
+    fn chain_then_actions() {
+        let outer = prompt(
+            r#"{"type": "prompt", "label": "From:", "then":
+                {"type": "prompt", "label": "To:", "context": "@main", "then":
+                  [{"type": "goto-line"}, {"type": "bookmark-set"}]}}"#,
+        );
+        assert_eq!(outer.label, "From:");
+        assert_eq!(outer.context, None);
+        let Action::Prompt(inner) = *outer.then else {
+            panic!("not a prompt: {:?}", outer.then);
+        };
+        assert_eq!(inner.label, "To:");
+        assert_eq!(inner.context.as_deref(), Some("@main"));
+        assert!(matches!(
+            &*inner.then,
+            Action::Multiple(actions)
+                if matches!(actions.as_slice(), [Action::GotoLine, Action::BookmarkSet(_)])
+        ));
+
+        let json = r#"{"type": "prompt", "label": "Goto:"}"#;
+        let json = nojson::RawJson::parse(json).expect("valid json");
+        assert!(Action::try_from(json.value()).is_err());
+    }
+
+    #[test]
+    fn history_per_prompt() {
+        let goto = PromptMode::new(prompt(
+            r#"{"type": "prompt", "label": "Goto:", "then": {"type": "goto-line"}}"#,
+        ));
+        let file_name = goto.history.path.file_name().expect("file name");
+        assert_eq!(file_name, ".kk.prompt-history.Goto");
+
+        let bookmark = PromptMode::new(prompt(
+            r#"{"type": "prompt", "label": "Set bookmark:", "history": "bookmark", "then": {"type": "bookmark-set"}}"#,
+        ));
+        let file_name = bookmark.history.path.file_name().expect("file name");
+        assert_eq!(file_name, ".kk.prompt-history.bookmark");
+    }
+
+    #[test]
+    fn cursor_after_label_and_input() {
+        let mut mode = PromptMode::new(prompt(
+            r#"{"type": "prompt", "label": "Goto:", "then": {"type": "goto-line"}}"#,
+        ));
+        mode.input.set_text("あa1");
+        mode.input.move_left();
+        let region = TerminalRegion {
+            position: TerminalPosition { row: 5, col: 0 },
+            size: TerminalSize { rows: 1, cols: 80 },
+        };
+        assert_eq!(
+            mode.cursor_position(region),
+            TerminalPosition { row: 5, col: 9 }
+        );
+    }
+
+    #[test]
+    fn pass_input_to_then_action() {
+        let path = std::env::temp_dir().join(format!("kk-test-{}-prompt", std::process::id()));
+        std::fs::write(&path, "a\nb\nc\n").expect("write");
+        let mut state = State::new(path.clone()).expect("load");
+        let _ = std::fs::remove_file(path);
+
+        state.prompt_value = Some("3".to_owned());
+        state.handle_goto_line().expect("goto");
+        assert_eq!(state.cursor.row, 2);
+        assert_eq!(
+            state.command_var(crate::action::CommandVar::Prompt, "@main"),
+            "3"
+        );
+    }
+}
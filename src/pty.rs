@@ -0,0 +1,174 @@
+//! Commands attached to a pseudo-terminal, so that they print progress as they would in a shell.
+use std::{
+    ffi::{CStr, OsStr},
+    fs::File,
+    io::Read,
+    os::{
+        fd::{AsRawFd, FromRawFd, RawFd},
+        unix::{ffi::OsStrExt, fs::OpenOptionsExt, process::CommandExt},
+    },
+    process::{Child, Command, ExitStatus, Stdio},
+};
+
+use orfail::OrFail;
+use tuinix::TerminalSize;
+
+#[derive(Debug)]
+pub struct PtyProcess {
+    child: Child,
+    master: File,
+}
+
+impl PtyProcess {
+    /// Spawns `cmd` with a new pty of `size` as its controlling terminal and standard streams.
+    pub fn spawn(mut cmd: Command, size: TerminalSize) -> orfail::Result<Self> {
+        let master = open_master().or_fail()?;
+        let slave = open_slave(&master).or_fail()?;
+
+        let winsize = libc::winsize {
+            ws_row: size.rows.min(u16::MAX as usize) as u16,
+            ws_col: size.cols.min(u16::MAX as usize) as u16,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+        check(unsafe { libc::ioctl(master.as_raw_fd(), libc::TIOCSWINSZ, &winsize) }).or_fail()?;
+
+        cmd.stdin(Stdio::from(slave.try_clone().or_fail()?));
+        cmd.stdout(Stdio::from(slave.try_clone().or_fail()?));
+        cmd.stderr(Stdio::from(slave));
+        unsafe {
+            cmd.pre_exec(|| {
+                // Start a new session so that the pty becomes the controlling terminal
+                check(libc::setsid())?;
+                check(libc::ioctl(0, libc::TIOCSCTTY as _, 0))?;
+                Ok(())
+            });
+        }
+        let child = cmd
+            .spawn()
+            .or_fail_with(|e| format!("Failed to execute command: {e}"))?;
+        drop(cmd); // Closes the parent's copies of the slave, so that EOF is detected
+
+        tuinix::set_nonblocking(master.as_raw_fd()).or_fail()?;
+        Ok(Self { child, master })
+    }
+
+    pub fn fd(&self) -> RawFd {
+        self.master.as_raw_fd()
+    }
+
+    /// Reads the currently available output into `buf`, returning `true` once the output has ended.
+    pub fn read_available(&mut self, buf: &mut Vec<u8>) -> orfail::Result<bool> {
+        let mut chunk = [0; 4096];
+        loop {
+            match tuinix::try_nonblocking(self.master.read(&mut chunk)) {
+                Ok(Some(0)) => return Ok(true),
+                Ok(Some(n)) => buf.extend_from_slice(&chunk[..n]),
+                Ok(None) => return Ok(false),
+                // Linux reports EIO once every slave has been closed
+                Err(e) if e.raw_os_error() == Some(libc::EIO) => return Ok(true),
+                Err(e) => return Err(e).or_fail(),
+            }
+        }
+    }
+
+    pub fn wait(&mut self) -> orfail::Result<ExitStatus> {
+        self.child
+            .wait()
+            .or_fail_with(|e| format!("Failed to wait for command: {e}"))
+    }
+}
+
+impl Drop for PtyProcess {
+    fn drop(&mut self) {
+        if matches!(self.child.try_wait(), Ok(None)) {
+            // The command leads its own session, so its whole process group is killed,
+            // including processes it has spawned such as compilers run by cargo
+            unsafe { libc::killpg(self.child.id() as libc::pid_t, libc::SIGKILL) };
+            let _ = self.child.wait();
+        }
+    }
+}
+
+fn open_master() -> std::io::Result<File> {
+    // O_CLOEXEC is set separately, as not every platform accepts it in posix_openpt()
+    let fd = check(unsafe { libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY) })?;
+    let master = unsafe { File::from_raw_fd(fd) };
+    check(unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) })?;
+    check(unsafe { libc::grantpt(fd) })?;
+    check(unsafe { libc::unlockpt(fd) })?;
+    Ok(master)
+}
+
+fn open_slave(master: &File) -> std::io::Result<File> {
+    std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NOCTTY | libc::O_CLOEXEC)
+        .open(slave_path(master)?)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn slave_path(master: &File) -> std::io::Result<std::path::PathBuf> {
+    let mut name = [0 as libc::c_char; 128];
+    let ret = unsafe { libc::ptsname_r(master.as_raw_fd(), name.as_mut_ptr(), name.len()) };
+    if ret != 0 {
+        return Err(std::io::Error::from_raw_os_error(ret));
+    }
+    let name = unsafe { CStr::from_ptr(name.as_ptr()) };
+    Ok(OsStr::from_bytes(name.to_bytes()).into())
+}
+
+// ptsname() returns a static buffer, which is fine here as ptys are only opened from the main thread
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn slave_path(master: &File) -> std::io::Result<std::path::PathBuf> {
+    let name = unsafe { libc::ptsname(master.as_raw_fd()) };
+    if name.is_null() {
+        return Err(std::io::Error::last_os_error());
+    }
+    let name = unsafe { CStr::from_ptr(name) };
+    Ok(OsStr::from_bytes(name.to_bytes()).into())
+}
+
+fn check(ret: libc::c_int) -> std::io::Result<libc::c_int> {
+    if ret < 0 {
+        Err(std::io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    fn run(script: &str) -> (Vec<u8>, ExitStatus) {
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", script]);
+        let size = TerminalSize { rows: 24, cols: 80 };
+        let mut process = PtyProcess::spawn(cmd, size).expect("spawn");
+        let mut output = Vec::new();
+        for _ in 0..1000 {
+            if process.read_available(&mut output).expect("read") {
+                return (output, process.wait().expect("wait"));
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        panic!("`{script}` did not finish");
+    }
+
+    #[test]
+    fn read_output_until_exit() {
+        let (output, status) = run("echo hello; exit 3");
+        assert_eq!(output, b"hello\r\n");
+        assert_eq!(status.code(), Some(3));
+    }
+
+    #[test]
+    fn run_on_terminal() {
+        let (output, _) = run("test -t 0 && test -t 1 && stty size");
+        assert_eq!(output, b"24 80\r\n");
+    }
+}
@@ -0,0 +1,196 @@
+//! Unix domain socket through which other processes send actions to a running kk.
+//!
+//! The protocol is line-based: a client sends one JSON-encoded action per line,
+//! and kk replies to each with either `ok` or `error: <MESSAGE>`.
+use std::{
+    io::{ErrorKind, Read, Write},
+    os::{
+        fd::{AsRawFd, RawFd},
+        unix::{
+            fs::FileTypeExt,
+            net::{UnixListener, UnixStream},
+        },
+    },
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use orfail::OrFail;
+
+use crate::action::Action;
+
+// A client that does not read its replies must not freeze kk
+const REPLY_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Debug)]
+pub struct RemoteServer {
+    path: PathBuf,
+    listener: UnixListener,
+    clients: Vec<RemoteClient>,
+}
+
+#[derive(Debug)]
+struct RemoteClient {
+    stream: UnixStream,
+    partial: Vec<u8>, // Bytes of the line being received
+}
+
+/// An action received from a client, to be answered with [`RemoteRequest::reply()`].
+#[derive(Debug)]
+pub struct RemoteRequest {
+    pub json: String,
+    stream: UnixStream,
+}
+
+impl RemoteServer {
+    pub fn bind(path: &Path) -> orfail::Result<Self> {
+        if let Ok(metadata) = path.symlink_metadata() {
+            // Only a socket left behind by a kk that has exited may be replaced
+            metadata
+                .file_type()
+                .is_socket()
+                .or_fail_with(|()| format!("{} exists and is not a socket", path.display()))?;
+            (UnixStream::connect(path).is_err())
+                .or_fail_with(|()| format!("socket {} is already in use", path.display()))?;
+            std::fs::remove_file(path)
+                .or_fail_with(|e| format!("failed to remove socket {}: {e}", path.display()))?;
+        }
+
+        // Only the user may connect, as actions can run arbitrary commands
+        let umask = unsafe { libc::umask(0o177) };
+        let listener = UnixListener::bind(path);
+        unsafe { libc::umask(umask) };
+        let listener =
+            listener.or_fail_with(|e| format!("failed to bind socket {}: {e}", path.display()))?;
+        listener.set_nonblocking(true).or_fail()?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            listener,
+            clients: Vec::new(),
+        })
+    }
+
+    pub fn fds(&self) -> Vec<RawFd> {
+        std::iter::once(self.listener.as_raw_fd())
+            .chain(self.clients.iter().map(|c| c.stream.as_raw_fd()))
+            .collect()
+    }
+
+    pub fn handle_fd_ready(&mut self, fd: RawFd) -> orfail::Result<Vec<RemoteRequest>> {
+        if fd == self.listener.as_raw_fd() {
+            self.accept().or_fail()?;
+            return Ok(Vec::new());
+        }
+        let Some(i) = self.clients.iter().position(|c| c.stream.as_raw_fd() == fd) else {
+            return Ok(Vec::new());
+        };
+
+        let client = &mut self.clients[i];
+        let eof = client.read_available();
+        let mut lines = Vec::new();
+        while let Some(i) = client.partial.iter().position(|&b| b == b'\n') {
+            let bytes = client.partial.drain(..=i).collect::<Vec<_>>();
+            lines.push(bytes[..i].to_vec());
+        }
+        if eof {
+            // The last action does not need a trailing newline
+            lines.push(std::mem::take(&mut client.partial));
+        }
+
+        let mut requests = Vec::new();
+        for line in lines {
+            let json = String::from_utf8_lossy(&line).trim().to_owned();
+            if json.is_empty() {
+                continue;
+            }
+            if let Ok(stream) = client.stream.try_clone() {
+                requests.push(RemoteRequest { json, stream });
+            }
+        }
+        if eof {
+            self.clients.swap_remove(i);
+        }
+        Ok(requests)
+    }
+
+    fn accept(&mut self) -> orfail::Result<()> {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    stream.set_nonblocking(true).or_fail()?;
+                    self.clients.push(RemoteClient {
+                        stream,
+                        partial: Vec::new(),
+                    });
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e).or_fail(),
+            }
+        }
+    }
+}
+
+impl Drop for RemoteServer {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+impl RemoteClient {
+    // Returns `true` once the client has closed its end, or the connection is broken
+    fn read_available(&mut self) -> bool {
+        let mut buf = [0; 4096];
+        loop {
+            match self.stream.read(&mut buf) {
+                Ok(0) => return true,
+                Ok(n) => self.partial.extend_from_slice(&buf[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return false,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(_) => return true,
+            }
+        }
+    }
+}
+
+impl RemoteRequest {
+    pub fn parse_action(&self) -> Result<Action, String> {
+        let json = nojson::RawJson::parse(&self.json).map_err(|e| e.to_string())?;
+        Action::try_from(json.value()).map_err(|e| e.to_string())
+    }
+
+    pub fn reply(mut self, result: Result<(), String>) {
+        let reply = match result {
+            Ok(()) => "ok".to_owned(),
+            Err(e) => format!("error: {}", e.replace('\n', " ")),
+        };
+        // A non-blocking write would drop a reply that the socket buffer cannot take at once.
+        // The stream shares its file status flags with the client being read, so they are restored.
+        // The client may have already gone away, which is not kk's concern.
+        let _ = self.stream.set_nonblocking(false);
+        let _ = self.stream.set_write_timeout(Some(REPLY_TIMEOUT));
+        let _ = self.stream.write_all(format!("{reply}\n").as_bytes());
+        let _ = self.stream.set_nonblocking(true);
+    }
+}
+
+/// Sends actions to the kk listening on `path`, failing if any of them is rejected.
+pub fn send_actions(path: &Path, actions: &[String]) -> orfail::Result<()> {
+    let mut stream = UnixStream::connect(path)
+        .or_fail_with(|e| format!("failed to connect to socket {}: {e}", path.display()))?;
+    for action in actions {
+        // Newlines are only allowed as whitespace in JSON, so each action fits on one line
+        let line = action.replace(['\n', '\r'], " ");
+        writeln!(stream, "{line}").or_fail()?;
+    }
+    stream.shutdown(std::net::Shutdown::Write).or_fail()?;
+
+    let mut replies = String::new();
+    stream.read_to_string(&mut replies).or_fail()?;
+    let errors = replies
+        .lines()
+        .filter_map(|line| line.strip_prefix("error: "))
+        .collect::<Vec<_>>();
+    errors.is_empty().or_fail_with(|()| errors.join("\n"))?;
+    Ok(())
+}
@@ -1,33 +1,72 @@
-use std::{collections::VecDeque, num::NonZeroUsize, path::PathBuf};
+use std::{
+    collections::VecDeque, num::NonZeroUsize, ops::Range, os::fd::RawFd, path::PathBuf,
+    time::Duration,
+};
 
+use mame::preview::{FilePreviewPaneSpec, FilePreviewSpec};
 use orfail::OrFail;
+use regex::Regex;
 use tuinix::{KeyCode, TerminalPosition, TerminalSize};
 
 use crate::{
-    action::{ExternalCommandAction, ExternalCommandArg},
-    anchor::CursorAnchor,
-    buffer::{TextBuffer, TextPosition},
+    action::{
+        BookmarkAction, CommandVar, ErrorListAction, ErrorPattern, ExternalCommandAction,
+        ExternalCommandArg, ExternalCommandOutput, ExternalCommandStdin, GotoAction, GrepScope,
+        PtyCommandAction, TextInsertAction,
+    },
+    anchor::{Bookmarks, CursorAnchor, is_same_path, relative_path},
+    buffer::{ReplacedLines, TextBuffer, TextEdit, TextLine, TextPosition},
     clipboard::Clipboard,
-    grep_mode::{GrepMode, Highlight},
+    command::{apply_command_env, resolve_cwd, run_command},
+    error_list::ErrorList,
+    grep_mode::{
+        GrepMode, Highlight, HighlightItem, expand_replacement, has_capture_reference,
+        results_file_path,
+    },
+    line_editor::{LineEditor, is_word_char},
+    output_pane::OutputPane,
+    preview::{Preview, PreviewPane},
+    prompt_mode::PromptMode,
+    pty::PtyProcess,
 };
 
 pub const MAX_HISTORY_SIZE: usize = 1000;
 
+/// How to restore the buffer to the state before an edit group.
+#[derive(Debug, Clone)]
+pub enum UndoEntry {
+    Snapshot(TextBuffer),
+    Lines {
+        replaced: Vec<ReplacedLines>, // Edits made by a line diff
+        dirty: bool,
+    },
+}
+
 #[derive(Debug)]
 pub struct State {
     pub path: PathBuf,
     pub cursor: TextPosition,
-    pub viewport: TextPosition, // Top-left position of the visible text area
+    pub cursors: Vec<TextPosition>, // Additional cursors that receive the same edits as `cursor`
+    cursors_synced: usize,          // Number of journaled edits already applied to `cursors`
+    pub viewport: TextPosition,     // Top-left position of the visible text area
     pub recenter_viewport: bool,
     pub buffer: TextBuffer,
     pub message: Option<String>,
+    pub failure: Option<String>, // Message of the last action that failed without an error
     pub mark: Option<TextPosition>,
+    pub rect_mark: bool, // Whether the mark describes a rectangle (column block)
     pub clipboard: Clipboard,
+    pub bookmarks: Bookmarks,
     pub editing: bool,
-    pub history: VecDeque<(TextPosition, TextBuffer)>,
+    pub history: VecDeque<(TextPosition, UndoEntry)>, // Cursor and buffer before each edit group
     pub undo_index: usize,
     pub grep_mode: Option<GrepMode>, // TODO: non-optional
     pub highlight: Highlight,
+    pub prompt: Option<PromptMode>,
+    pub prompt_value: Option<String>, // Submitted prompt input, while the prompt's action runs
+    pub output: Option<OutputPane>,   // Output of the last pty command
+    pub errors: Option<ErrorList>,
+    pub preview: Option<Preview>,
 }
 
 impl State {
@@ -37,17 +76,27 @@ impl State {
         Ok(Self {
             path,
             cursor: TextPosition::default(),
+            cursors: Vec::new(),
+            cursors_synced: 0,
             viewport: TextPosition::default(),
             recenter_viewport: false,
             buffer,
             message: None,
+            failure: None,
             mark: None,
+            rect_mark: false,
             clipboard: Clipboard::default(),
+            bookmarks: Bookmarks::default(),
             editing: false,
             history: VecDeque::new(),
             undo_index: 0,
             grep_mode: None,
             highlight: Highlight::default(),
+            prompt: None,
+            prompt_value: None,
+            output: None,
+            errors: None,
+            preview: None,
         })
     }
 
@@ -55,13 +104,31 @@ impl State {
         self.message = Some(message.into());
     }
 
-    pub fn restore_anchor(&mut self, anchor: &CursorAnchor) -> orfail::Result<()> {
+    /// Shows `message` as the reason an action did nothing, also reporting it to remote clients.
+    pub fn set_failure_message(&mut self, message: impl Into<String>) {
+        let message = message.into();
+        self.failure = Some(message.clone());
+        self.message = Some(message);
+    }
+
+    /// Moves the cursor to `anchor`, opening its file if it is not the current one.
+    ///
+    /// Returns `false` without moving if the current buffer has unsaved changes
+    /// that opening another file would discard.
+    pub fn restore_anchor(&mut self, anchor: &CursorAnchor) -> orfail::Result<bool> {
         self.finish_editing();
-        if self.path != anchor.path {
-            // TODO: dirty check
+        if !is_same_path(&self.path, &anchor.path) {
+            if self.buffer.dirty {
+                self.set_failure_message(format!(
+                    "Unsaved changes (save before opening {})",
+                    anchor.path.display()
+                ));
+                return Ok(false);
+            }
 
             self.buffer.load_file(&anchor.path).or_fail()?;
-            self.path = anchor.path.clone();
+            self.path = relative_path(&anchor.path);
+            self.cursors.clear();
 
             // TODO: keep undo history
             self.history.clear();
@@ -73,7 +140,7 @@ impl State {
             .col_at_char_index(self.cursor.row, anchor.char.get() - 1)
             .unwrap_or_default();
         self.recenter_viewport = true;
-        Ok(())
+        Ok(true)
     }
 
     pub fn terminal_cursor_position(&self) -> TerminalPosition {
@@ -129,14 +196,19 @@ impl State {
             return;
         }
 
+        self.push_history(self.cursor, UndoEntry::Snapshot(self.buffer.clone()));
+        self.editing = true;
+    }
+
+    fn push_history(&mut self, cursor: TextPosition, entry: UndoEntry) {
+        // Undone entries are dropped, as `UndoEntry::Lines` only applies to the text right after its edits
+        self.history.truncate(self.undo_index);
         while self.history.len() >= MAX_HISTORY_SIZE {
             self.history.pop_front();
         }
 
-        self.history.push_back((self.cursor, self.buffer.clone()));
+        self.history.push_back((cursor, entry));
         self.undo_index = self.history.len();
-
-        self.editing = true;
     }
 
     pub fn finish_editing(&mut self) {
@@ -154,8 +226,13 @@ impl State {
     }
 
     pub fn handle_cursor_left(&mut self) {
+        if let Some(prompt) = &mut self.prompt {
+            prompt.input.move_left();
+            return;
+        }
+
         if let Some(grep) = &mut self.grep_mode {
-            grep.cursor = grep.cursor.saturating_sub(1);
+            grep.query.move_left();
             return;
         }
 
@@ -171,8 +248,13 @@ impl State {
     }
 
     pub fn handle_cursor_right(&mut self) {
+        if let Some(prompt) = &mut self.prompt {
+            prompt.input.move_right();
+            return;
+        }
+
         if let Some(grep) = &mut self.grep_mode {
-            grep.cursor = (grep.cursor + 1).min(grep.query.len());
+            grep.query.move_right();
             return;
         }
 
@@ -189,8 +271,13 @@ impl State {
     }
 
     pub fn handle_cursor_line_start(&mut self) {
+        if let Some(prompt) = &mut self.prompt {
+            prompt.input.move_start();
+            return;
+        }
+
         if let Some(grep) = &mut self.grep_mode {
-            grep.cursor = 0;
+            grep.query.move_start();
             return;
         }
 
@@ -199,8 +286,13 @@ impl State {
     }
 
     pub fn handle_cursor_line_end(&mut self) {
+        if let Some(prompt) = &mut self.prompt {
+            prompt.input.move_end();
+            return;
+        }
+
         if let Some(grep) = &mut self.grep_mode {
-            grep.cursor = grep.query.len();
+            grep.query.move_end();
             return;
         }
 
@@ -220,52 +312,212 @@ impl State {
     }
 
     pub fn handle_char_delete_backward(&mut self) {
+        if let Some(prompt) = &mut self.prompt {
+            prompt.input.delete_backward();
+            return;
+        }
+
         if let Some(grep) = &mut self.grep_mode {
-            if grep.cursor > 0 {
-                grep.query.remove(grep.cursor - 1);
-                grep.cursor -= 1;
+            if grep.query.delete_backward() {
                 self.regrep();
             }
             return;
         }
 
         self.start_editing();
+        if !self.cursors.is_empty() {
+            self.edit_at_cursors(|buffer, pos| {
+                buffer.delete_char_before(pos).map(|start| TextEdit {
+                    start,
+                    old_end: pos,
+                    new_end: start,
+                })
+            });
+            return;
+        }
+
         if let Some(new_pos) = self.buffer.delete_char_before(self.cursor) {
             self.cursor = new_pos;
         }
     }
 
     pub fn handle_char_delete_forward(&mut self) {
+        if let Some(prompt) = &mut self.prompt {
+            prompt.input.delete_forward();
+            return;
+        }
+
         if let Some(grep) = &mut self.grep_mode {
-            if grep.cursor < grep.query.len() {
-                grep.query.remove(grep.cursor);
+            if grep.query.delete_forward() {
                 self.regrep();
             }
             return;
         }
 
         self.start_editing();
+        if !self.cursors.is_empty() {
+            self.edit_at_cursors(|buffer, pos| {
+                let old_end = match buffer
+                    .text
+                    .get(pos.row)
+                    .and_then(|l| l.char_at_col(pos.col))
+                {
+                    Some(ch) => TextPosition {
+                        row: pos.row,
+                        col: pos.col + mame::terminal::char_cols(ch),
+                    },
+                    None => TextPosition {
+                        row: pos.row + 1,
+                        col: 0,
+                    },
+                };
+                buffer.delete_char_at(pos).then_some(TextEdit {
+                    start: pos,
+                    old_end,
+                    new_end: pos,
+                })
+            });
+            return;
+        }
+
         self.buffer.delete_char_at(self.cursor);
     }
 
+    // Shifts the additional cursors by the edits made at the main cursor only, e.g., by undo
+    fn sync_cursors(&mut self) {
+        let journal = &self.buffer.journal;
+        for edit in &journal[self.cursors_synced.min(journal.len())..] {
+            for pos in &mut self.cursors {
+                *pos = edit.shift_position(*pos);
+            }
+        }
+        self.cursors_synced = journal.len();
+    }
+
+    /// Applies an edit at every cursor, shifting the other cursors by the change it made.
+    fn edit_at_cursors<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&mut TextBuffer, TextPosition) -> Option<TextEdit>,
+    {
+        self.sync_cursors();
+        let main = self.cursor_position();
+        let mut positions = self
+            .cursors
+            .iter()
+            .map(|&pos| self.buffer.adjust_to_char_boundary(pos, true))
+            .chain(std::iter::once(main))
+            .collect::<Vec<_>>();
+        positions.sort();
+        positions.dedup();
+        let mut main_index = positions.binary_search(&main).unwrap_or_default();
+
+        for i in 0..positions.len() {
+            if i > 0 && positions[i - 1] == positions[i] {
+                // Cursors that were merged by a preceding edit are edited only once
+                continue;
+            }
+            let Some(edit) = f(&mut self.buffer, positions[i]) else {
+                continue;
+            };
+            for (j, pos) in positions.iter_mut().enumerate() {
+                *pos = if i == j {
+                    edit.new_end
+                } else {
+                    edit.shift_position(*pos)
+                };
+            }
+        }
+
+        let main = positions[main_index];
+        positions.dedup();
+        main_index = positions.binary_search(&main).unwrap_or_default();
+        self.cursor = positions.remove(main_index);
+        self.cursors = positions;
+        self.cursors_synced = self.buffer.journal.len();
+    }
+
+    pub fn handle_multi_cursor_from_hits(&mut self) {
+        let Some(grep) = &self.grep_mode else {
+            self.set_message("Not in grep mode");
+            return;
+        };
+
+        let mut positions = grep
+            .target_hits(&self.highlight)
+            .map(|item| item.start_position)
+            .collect::<Vec<_>>();
+        if positions.is_empty() {
+            self.set_message("No grep hits available");
+            return;
+        }
+
+        self.finish_editing();
+
+        // Keep the last hit starting at or before the cursor (the one under it, if any) as the main cursor,
+        // or the first hit if there is no such hit
+        let current_pos = self.cursor_position();
+        let main_index = positions
+            .iter()
+            .rposition(|&pos| pos <= current_pos)
+            .unwrap_or_default();
+        self.cursor = positions.remove(main_index);
+        self.cursors = positions;
+        self.cursors_synced = self.buffer.journal.len();
+        self.set_message(format!("Created {} cursors", self.cursors.len() + 1));
+    }
+
+    pub fn handle_multi_cursor_clear(&mut self) {
+        if self.cursors.is_empty() {
+            self.set_message("No multiple cursors");
+            return;
+        }
+        self.cursors.clear();
+        self.set_message("Collapsed to a single cursor");
+    }
+
     fn regrep(&mut self) {
         let Some(grep) = &mut self.grep_mode else {
             return;
         };
-        match grep.grep(&self.buffer) {
+        match grep.grep(&self.buffer, &self.path) {
             Err(e) => self.set_message(e.message),
-            Ok(highlight) => {
-                self.highlight = highlight;
-                if !self.highlight.contains(self.cursor) {
-                    if grep.action.forward {
-                        self.handle_grep_next_hit();
-                    } else {
-                        self.handle_grep_prev_hit();
-                    }
-                }
-                self.set_message(format!("Hit: {}", self.highlight.items.len()));
+            Ok(None) => self.set_message("Searching..."),
+            Ok(Some(highlight)) => self.apply_grep_result(highlight),
+        }
+    }
+
+    /// Reads the output of the running grep command, applying the result once it finishes.
+    pub fn handle_grep_fd_ready(&mut self, fd: RawFd) {
+        let Some(grep) = &mut self.grep_mode else {
+            return;
+        };
+        match grep.handle_fd_ready(fd, &self.buffer) {
+            Err(e) => self.set_message(e.message),
+            Ok(None) => {}
+            Ok(Some(highlight)) => self.apply_grep_result(highlight),
+        }
+    }
+
+    fn apply_grep_result(&mut self, highlight: Highlight) {
+        let Some(grep) = &self.grep_mode else {
+            return;
+        };
+        self.highlight = highlight;
+        if matches!(grep.action.scope, GrepScope::Project { .. }) {
+            // Jumping to other files while typing the query would be disruptive,
+            // so the results are only navigated explicitly by grep-next-hit / grep-prev-hit.
+            let hits = grep.project_hits.len();
+            self.set_message(format!("Hit: {hits} (project)"));
+            return;
+        }
+        if !self.highlight.contains(self.cursor) {
+            if grep.action.forward {
+                self.handle_grep_next_hit();
+            } else {
+                self.handle_grep_prev_hit();
             }
         }
+        self.set_message(format!("Hit: {}", self.highlight.items.len()));
     }
 
     pub fn handle_buffer_save(&mut self) -> orfail::Result<()> {
@@ -276,34 +528,32 @@ impl State {
 
     pub fn handle_buffer_reload(&mut self) -> orfail::Result<()> {
         self.finish_editing();
-        self.start_editing();
-
-        // Reload the buffer from file
-        self.buffer.load_file(&self.path).or_fail()?;
-
-        // Try to preserve cursor position, but adjust if the file has changed
-        let max_row = self.buffer.rows();
-        self.cursor.row = self.cursor.row.min(max_row);
-
-        if self.cursor.row < max_row {
-            let max_col = self.buffer.cols(self.cursor.row);
-            self.cursor.col = self.cursor.col.min(max_col);
-        } else {
-            self.cursor.col = 0;
-        }
 
-        // Adjust cursor to proper character boundary
-        self.cursor = self.buffer.adjust_to_char_boundary(self.cursor, true);
+        // Apply the file content as a diff, so that the cursor stays on the same line
+        let text = std::fs::read_to_string(&self.path)
+            .or_fail_with(|e| format!("failed to read file {}: {e}", self.path.display()))?;
+        self.apply_line_diff(&text);
+        self.buffer.dirty = false;
+        self.cursors.clear();
 
         self.set_message(format!("Reloaded: {}", self.path.display()));
-        self.finish_editing();
         Ok(())
     }
 
     pub fn handle_char_insert(&mut self, key: tuinix::KeyInput) {
+        if let Some(prompt) = &mut self.prompt {
+            if let KeyCode::Char(ch) = key.code {
+                prompt.input.insert_char(ch);
+            }
+            return;
+        }
+
         if let Some(grep) = &mut self.grep_mode {
-            grep.handle_char_insert(key);
-            self.regrep();
+            if let KeyCode::Char(ch) = key.code
+                && grep.query.insert_char(ch)
+            {
+                self.regrep();
+            }
             return;
         }
 
@@ -312,6 +562,17 @@ impl State {
         if let KeyCode::Char(ch) = key.code
             && !ch.is_control()
         {
+            if !self.cursors.is_empty() {
+                self.edit_at_cursors(|buffer, pos| {
+                    let new_end = buffer.insert_char_at(pos, ch);
+                    Some(TextEdit {
+                        start: pos,
+                        old_end: pos,
+                        new_end,
+                    })
+                });
+                return;
+            }
             self.cursor = self.buffer.insert_char_at(self.cursor, ch);
         }
     }
@@ -319,7 +580,18 @@ impl State {
     pub fn handle_newline_insert(&mut self) {
         self.finish_editing();
         self.start_editing();
-        self.cursor = self.buffer.insert_newline_at(self.cursor);
+        if !self.cursors.is_empty() {
+            self.edit_at_cursors(|buffer, pos| {
+                let new_end = buffer.insert_newline_at(pos);
+                Some(TextEdit {
+                    start: pos,
+                    old_end: pos,
+                    new_end,
+                })
+            });
+        } else {
+            self.cursor = self.buffer.insert_newline_at(self.cursor);
+        }
         self.finish_editing();
     }
 
@@ -335,9 +607,19 @@ impl State {
             return;
         };
 
-        let (cursor, buffer) = self.history[i].clone();
+        let (cursor, entry) = self.history[i].clone();
         self.cursor = cursor;
-        self.buffer = buffer;
+        match entry {
+            UndoEntry::Snapshot(buffer) => {
+                // Restored as line edits so that the mark, the cursors and the anchors follow the text
+                self.buffer.replace_lines(&buffer.text);
+                self.buffer.dirty = buffer.dirty;
+            }
+            UndoEntry::Lines { replaced, dirty } => {
+                self.buffer.revert_lines(&replaced);
+                self.buffer.dirty = dirty;
+            }
+        }
         self.undo_index = i;
         self.set_message(format!("Undo ({})", self.history.len() - i));
     }
@@ -348,26 +630,285 @@ impl State {
         let cursor_pos = self.cursor_position();
         if self.mark == Some(cursor_pos) {
             // If mark is already at cursor position, deactivate it
-            self.mark = None;
+            self.clear_mark();
             self.set_message("Mark deactivated");
         } else {
             // Set mark at current cursor position
             self.mark = Some(cursor_pos);
+            self.rect_mark = false;
             self.set_message("Mark set");
         }
     }
 
-    pub fn handle_mark_copy(&mut self) -> orfail::Result<()> {
+    pub fn handle_rect_mark_set(&mut self) {
         self.finish_editing();
 
-        if let Some(mark_pos) = self.mark.take() {
-            let cursor_pos = self.cursor_position();
-            let (start, end) = if mark_pos <= cursor_pos {
-                (mark_pos, cursor_pos)
-            } else {
-                (cursor_pos, mark_pos)
+        let cursor_pos = self.cursor_position();
+        if self.rect_mark && self.mark == Some(cursor_pos) {
+            self.clear_mark();
+            self.set_message("Rectangle mark deactivated");
+        } else {
+            self.mark = Some(cursor_pos);
+            self.rect_mark = true;
+            self.set_message("Rectangle mark set");
+        }
+    }
+
+    pub fn clear_mark(&mut self) {
+        self.mark = None;
+        self.rect_mark = false;
+    }
+
+    /// Returns the (start, end) positions of the linear region between the mark and the cursor.
+    ///
+    /// Returns `None` for a rectangle mark, whose region is given by [`State::rect_region()`].
+    pub fn mark_region(&self) -> Option<(TextPosition, TextPosition)> {
+        if self.rect_mark {
+            return None;
+        }
+        let mark_pos = self.mark?;
+        let cursor_pos = self.cursor_position();
+        if mark_pos <= cursor_pos {
+            Some((mark_pos, cursor_pos))
+        } else {
+            Some((cursor_pos, mark_pos))
+        }
+    }
+
+    /// Returns the top-left and bottom-right corners of the rectangle spanned by the mark and the cursor.
+    ///
+    /// Rows are inclusive on both ends, while the right column is exclusive.
+    pub fn rect_region(&self) -> Option<(TextPosition, TextPosition)> {
+        if !self.rect_mark {
+            return None;
+        }
+        let mark_pos = self.mark?;
+        let cursor_pos = self.cursor_position();
+        let top_left = TextPosition {
+            row: mark_pos.row.min(cursor_pos.row),
+            col: mark_pos.col.min(cursor_pos.col),
+        };
+        let bottom_right = TextPosition {
+            row: mark_pos.row.max(cursor_pos.row),
+            col: mark_pos.col.max(cursor_pos.col),
+        };
+        Some((top_left, bottom_right))
+    }
+
+    pub fn handle_rect_copy(&mut self) -> orfail::Result<()> {
+        self.finish_editing();
+
+        let Some((top_left, bottom_right)) = self.rect_region() else {
+            self.set_message("No rectangle mark set");
+            return Ok(());
+        };
+
+        let text = self.get_text_in_rect(top_left, bottom_right);
+        self.clipboard.write(&text).or_fail()?;
+        self.clear_mark();
+        self.set_message(format!(
+            "Copied rectangle ({} lines)",
+            bottom_right.row - top_left.row + 1
+        ));
+        Ok(())
+    }
+
+    pub fn handle_rect_cut(&mut self) -> orfail::Result<()> {
+        self.finish_editing();
+
+        let Some((top_left, bottom_right)) = self.rect_region() else {
+            self.set_message("No rectangle mark set");
+            return Ok(());
+        };
+
+        let text = self.get_text_in_rect(top_left, bottom_right);
+
+        self.start_editing();
+        for row in top_left.row..=bottom_right.row {
+            if let Some(line) = self.buffer.text.get_mut(row) {
+                let range = line.char_range_in_cols(top_left.col, bottom_right.col);
+                let start = TextPosition {
+                    row,
+                    col: line.col_at_char_index(range.start),
+                };
+                let old_end = TextPosition {
+                    row,
+                    col: line.col_at_char_index(range.end),
+                };
+                line.0.drain(range);
+                self.buffer.record_edit(TextEdit {
+                    start,
+                    old_end,
+                    new_end: start,
+                });
+            }
+        }
+        self.buffer.dirty = true;
+        self.cursor = self.buffer.adjust_to_char_boundary(top_left, true);
+        self.clear_mark();
+        self.finish_editing();
+
+        self.clipboard.write(&text).or_fail()?;
+        self.set_message(format!(
+            "Cut rectangle ({} lines)",
+            bottom_right.row - top_left.row + 1
+        ));
+        Ok(())
+    }
+
+    pub fn handle_rect_paste(&mut self) -> orfail::Result<()> {
+        self.finish_editing();
+
+        let text = self.clipboard.read().or_fail()?;
+        if text.is_empty() {
+            self.set_message("Clipboard is empty");
+            return Ok(());
+        }
+
+        // Each clipboard line is inserted at the cursor column of successive rows
+        let cursor_pos = self.cursor_position();
+        let lines: Vec<Vec<char>> = text.lines().map(|line| line.chars().collect()).collect();
+
+        self.start_editing();
+        for (i, chars) in lines.iter().enumerate() {
+            let row = cursor_pos.row + i;
+            while row >= self.buffer.text.len() {
+                self.append_line();
+            }
+            let line = &mut self.buffer.text[row];
+            let old_cols = line.cols();
+            line.pad_to_col(cursor_pos.col);
+            let col = line.adjust_to_char_boundary(cursor_pos.col, false);
+            line.insert_chars_at_col(col, chars);
+
+            // The padding is part of the inserted text
+            let start = TextPosition {
+                row,
+                col: col.min(old_cols),
+            };
+            self.cursor = TextPosition {
+                row,
+                col: col
+                    + chars
+                        .iter()
+                        .copied()
+                        .map(mame::terminal::char_cols)
+                        .sum::<usize>(),
+            };
+            self.buffer.record_edit(TextEdit {
+                start,
+                old_end: start,
+                new_end: self.cursor,
+            });
+        }
+        self.buffer.dirty = true;
+        self.finish_editing();
+
+        self.set_message(format!("Pasted rectangle ({} lines)", lines.len()));
+        Ok(())
+    }
+
+    pub fn handle_rect_insert(&mut self) -> orfail::Result<()> {
+        self.finish_editing();
+
+        let Some((top_left, bottom_right)) = self.rect_region() else {
+            self.set_message("No rectangle mark set");
+            return Ok(());
+        };
+
+        let text = self.clipboard.read().or_fail()?;
+        let Some(chars) = text
+            .lines()
+            .next()
+            .map(|line| line.chars().collect::<Vec<_>>())
+            .filter(|chars| !chars.is_empty())
+        else {
+            self.set_message("Clipboard is empty");
+            return Ok(());
+        };
+
+        self.start_editing();
+        for row in top_left.row..=bottom_right.row {
+            let Some(line) = self.buffer.text.get_mut(row) else {
+                continue;
             };
+            let old_cols = line.cols();
+            line.pad_to_col(top_left.col);
+            let col = line.adjust_to_char_boundary(top_left.col, false);
+            line.insert_chars_at_col(col, &chars);
+
+            // The padding is part of the inserted text
+            let start = TextPosition {
+                row,
+                col: col.min(old_cols),
+            };
+            let width = chars
+                .iter()
+                .copied()
+                .map(mame::terminal::char_cols)
+                .sum::<usize>();
+            self.buffer.record_edit(TextEdit {
+                start,
+                old_end: start,
+                new_end: TextPosition {
+                    row,
+                    col: col + width,
+                },
+            });
+        }
+        self.buffer.dirty = true;
+        self.cursor = self.buffer.adjust_to_char_boundary(top_left, true);
+        self.clear_mark();
+        self.finish_editing();
+
+        self.set_message(format!(
+            "Inserted {} characters into {} lines",
+            chars.len(),
+            bottom_right.row - top_left.row + 1
+        ));
+        Ok(())
+    }
+
+    // Appends an empty line as a newline at the end of the buffer, so that the edit is journaled
+    fn append_line(&mut self) {
+        match self.buffer.text.last() {
+            Some(last) => {
+                let end = TextPosition {
+                    row: self.buffer.text.len() - 1,
+                    col: last.cols(),
+                };
+                self.buffer.insert_newline_at(end);
+            }
+            None => self.buffer.text.push(TextLine::default()),
+        }
+    }
+
+    // Helper method to get text in a rectangle (one clipboard line per row)
+    fn get_text_in_rect(&self, top_left: TextPosition, bottom_right: TextPosition) -> String {
+        let mut lines = Vec::new();
+        for row in top_left.row..=bottom_right.row {
+            let text = self
+                .buffer
+                .text
+                .get(row)
+                .map(|line| {
+                    let range = line.char_range_in_cols(top_left.col, bottom_right.col);
+                    line.0[range].iter().collect::<String>()
+                })
+                .unwrap_or_default();
+            lines.push(text);
+        }
+        lines.join("\n")
+    }
+
+    pub fn handle_mark_copy(&mut self) -> orfail::Result<()> {
+        if self.rect_mark {
+            return self.handle_rect_copy().or_fail();
+        }
+        self.finish_editing();
 
+        if let Some((start, end)) = self.mark_region() {
+            self.clear_mark();
             if let Some(text) = self.get_text_in_range(start, end) {
                 self.clipboard.write(&text).or_fail()?;
                 self.set_message(format!("Copied {} characters", text.len()));
@@ -381,21 +922,17 @@ impl State {
     }
 
     pub fn handle_mark_cut(&mut self) -> orfail::Result<()> {
+        if self.rect_mark {
+            return self.handle_rect_cut().or_fail();
+        }
         self.finish_editing();
 
-        if let Some(mark_pos) = self.mark.take() {
-            let cursor_pos = self.cursor_position();
-            let (start, end) = if mark_pos <= cursor_pos {
-                (mark_pos, cursor_pos)
-            } else {
-                (cursor_pos, mark_pos)
-            };
-
+        if let Some((start, end)) = self.mark_region() {
+            self.clear_mark();
             if let Some(text) = self.get_text_in_range(start, end) {
                 // Delete the selected text
                 self.delete_text_in_range(start, end);
                 self.cursor = start;
-                self.mark = None;
 
                 self.clipboard.write(&text).or_fail()?;
                 self.set_message(format!("Cut {} characters", text.len()));
@@ -468,6 +1005,11 @@ impl State {
 
         // TODO: This should be implemented as a compound undo action
         // For now, we'll do a simple implementation
+        self.buffer.record_edit(TextEdit {
+            start,
+            old_end: end,
+            new_end: start,
+        });
 
         if start.row == end.row {
             // Single line deletion
@@ -520,46 +1062,76 @@ impl State {
     }
 
     pub fn handle_clipboard_paste(&mut self) -> orfail::Result<()> {
-        if let Some(grep) = &mut self.grep_mode {
-            let text = self.clipboard.read().or_fail()?;
+        let text = self.clipboard.read().or_fail()?;
+        if text.is_empty() && self.prompt.is_none() {
+            self.set_message("Clipboard is empty");
+            return Ok(());
+        }
+        self.insert_text(&text);
+        Ok(())
+    }
 
-            if text.is_empty() {
-                self.set_message("Clipboard is empty");
-                return Ok(());
-            }
+    pub fn handle_text_insert(&mut self, action: &TextInsertAction) {
+        self.insert_text(&action.text);
+    }
 
-            // Insert clipboard text at current cursor position in grep query
-            for ch in text.chars() {
-                // Skip control characters and newlines in grep query
-                if !ch.is_control() {
-                    grep.query.insert(grep.cursor, ch);
-                    grep.cursor += 1;
-                }
+    // Inserts the text into the prompt, the grep query or the buffer, whichever is active
+    fn insert_text(&mut self, text: &str) {
+        if text.is_empty() {
+            return;
+        }
+
+        // Prompts and the grep query are a single line, so only the first line is inserted
+        let multi_line = text.trim_end_matches(['\r', '\n']).contains('\n');
+        if let Some(prompt) = &mut self.prompt {
+            prompt.input.insert_str(text);
+            if multi_line {
+                self.set_message("Pasted only the first line");
             }
+            return;
+        }
+
+        if let Some(grep) = &mut self.grep_mode {
+            // Insert the text at current cursor position in grep query
+            grep.query.insert_str(text);
 
             // Re-run the grep with updated query
             self.regrep();
-            return Ok(());
+            if multi_line {
+                self.set_message("Pasted only the first line");
+            }
+            return;
         };
 
         self.finish_editing();
 
-        let text = self.clipboard.read().or_fail()?;
-
-        if text.is_empty() {
-            self.set_message("Clipboard is empty");
-            return Ok(());
-        }
-
         // Split text into lines
         let lines: Vec<&str> = text.lines().collect();
 
         if lines.is_empty() {
             self.set_message("Nothing to paste");
-            return Ok(());
+            return;
         }
         self.start_editing();
 
+        if !self.cursors.is_empty() {
+            self.edit_at_cursors(|buffer, pos| {
+                let new_end = buffer.insert_str_at(pos, text);
+                Some(TextEdit {
+                    start: pos,
+                    old_end: pos,
+                    new_end,
+                })
+            });
+            self.set_message(format!(
+                "Pasted {} lines at {} cursors",
+                lines.len(),
+                self.cursors.len() + 1
+            ));
+            self.finish_editing();
+            return;
+        }
+
         // Insert the text
         if lines.len() == 1 {
             // Single line paste
@@ -597,157 +1169,430 @@ impl State {
         }
 
         self.finish_editing();
-        Ok(())
     }
 
-    pub fn handle_external_command(
-        &mut self,
-        action: &ExternalCommandAction,
-    ) -> orfail::Result<()> {
-        self.finish_editing();
+    pub fn command_args(&self, args: &[ExternalCommandArg], context: &str) -> Vec<String> {
+        args.iter()
+            .map(|arg| match arg {
+                ExternalCommandArg::Literal(a) => a.clone(),
+                ExternalCommandArg::Var(var) => self.command_var(*var, context),
+            })
+            .collect()
+    }
 
-        let mut cmd = std::process::Command::new(&action.command);
+    pub fn command_envs(&self, context: &str) -> Vec<(String, String)> {
+        CommandVar::ALL
+            .into_iter()
+            .map(|var| (format!("KK_{}", var.name()), self.command_var(var, context)))
+            .collect()
+    }
 
-        for arg in &action.args {
-            match arg {
-                ExternalCommandArg::Literal(a) => cmd.arg(a),
-                ExternalCommandArg::CurrentFile => cmd.arg(self.path.display().to_string()),
-            };
+    pub fn command_var(&self, var: CommandVar, context: &str) -> String {
+        let mark_range = self.mark.map(|mark| {
+            let cursor = self.cursor_position();
+            (mark.min(cursor), mark.max(cursor))
+        });
+        match var {
+            CommandVar::CurrentFile => self.path.display().to_string(),
+            CommandVar::Prompt => self.prompt_value.clone().unwrap_or_default(),
+            CommandVar::CursorLine => self.current_cursor_anchor().line.to_string(),
+            CommandVar::CursorColumn => self.current_cursor_anchor().char.to_string(),
+            CommandVar::Word => self.word_at_cursor().unwrap_or_default(),
+            CommandVar::MarkStart | CommandVar::MarkEnd => {
+                let Some((start, end)) = mark_range else {
+                    return String::new();
+                };
+                let pos = if var == CommandVar::MarkStart {
+                    start
+                } else {
+                    end
+                };
+                let anchor = self.anchor_at(pos);
+                format!("{}:{}", anchor.line, anchor.char)
+            }
+            CommandVar::Context => context.to_owned(),
+            CommandVar::ClipboardFile => self.clipboard.path.display().to_string(),
+            CommandVar::GrepQuery => self
+                .grep_mode
+                .as_ref()
+                .map(|grep| grep.query.to_text())
+                .unwrap_or_default(),
+            CommandVar::Dirty => if self.buffer.dirty { "1" } else { "0" }.to_owned(),
         }
+    }
 
-        let stdin_input = if let Some(mark_pos) = self.mark {
-            let cursor_pos = self.cursor_position();
-            let (start, end) = if mark_pos <= cursor_pos {
-                (mark_pos, cursor_pos)
-            } else {
-                (cursor_pos, mark_pos)
-            };
-            self.get_text_in_range(start, end)
-        } else {
-            None
-        };
-        cmd.stdin(std::process::Stdio::piped());
-        cmd.stdout(std::process::Stdio::piped());
-        cmd.stderr(std::process::Stdio::piped());
-        cmd.env("KK_CURRENT_FILE", self.path.display().to_string());
+    fn word_at_cursor(&self) -> Option<String> {
+        let line = self.buffer.text.get(self.cursor.row)?;
+        let index = line.char_index_at_col(self.cursor.col);
+        let mut start = index;
+        while start > 0 && is_word_char(line.0[start - 1]) {
+            start -= 1;
+        }
+        let mut end = index;
+        while end < line.len() && is_word_char(line.0[end]) {
+            end += 1;
+        }
+        (start < end).then(|| line.0[start..end].iter().collect())
+    }
 
-        let mut child = match cmd.spawn() {
-            Err(e) => {
-                self.set_message(format!("Failed to execute command: {}", e));
-                return Ok(());
-            }
-            Ok(child) => child,
-        };
+    /// Runs an external command and delivers its output as `action.output` specifies.
+    ///
+    /// `interrupted` is polled while the command runs, as in [`run_command()`].
+    pub fn handle_external_command<F>(
+        &mut self,
+        action: &ExternalCommandAction,
+        context: &str,
+        interrupted: F,
+    ) -> orfail::Result<()>
+    where
+        F: FnMut(Duration) -> orfail::Result<bool>,
+    {
+        self.finish_editing();
 
-        // Write to stdin if we have marked text
-        if let Some(mut stdin) = child.stdin.take()
-            && let Some(text) = stdin_input {
-                use std::io::Write;
-                let _ = stdin.write_all(text.as_bytes());
+        let mut cmd = std::process::Command::new(&action.command);
+        cmd.args(self.command_args(&action.args, context));
+        cmd.envs(self.command_envs(context));
+        apply_command_env(&mut cmd, &action.env, &self.path);
+
+        let uses_mark = action.stdin == ExternalCommandStdin::Mark
+            || action.output == ExternalCommandOutput::ReplaceMark;
+        if uses_mark && self.rect_mark && self.mark.is_some() {
+            self.set_failure_message("The command's mark options do not support a rectangle mark");
+            return Ok(());
+        }
+        let mark_range = self.mark_region();
+        let stdin_input = match action.stdin {
+            ExternalCommandStdin::Mark => {
+                mark_range.and_then(|(start, end)| self.get_text_in_range(start, end))
             }
+            ExternalCommandStdin::Buffer => Some(self.buffer.to_single_text()),
+            ExternalCommandStdin::Line => self
+                .buffer
+                .text
+                .get(self.cursor.row)
+                .map(|line| format!("{line}\n")),
+            ExternalCommandStdin::None => None,
+        };
 
-        let output = match child.wait_with_output() {
+        let stdin_input = stdin_input.map(String::into_bytes);
+        let output = match run_command(cmd, stdin_input, action.timeout, interrupted) {
             Err(e) => {
-                self.set_message(format!("Failed to wait for command: {}", e));
+                self.set_failure_message(format!("Failed to execute command: {}", e.message));
                 return Ok(());
             }
             Ok(output) => output,
         };
 
-        if !output.status.success() {
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            self.set_message(format!("Command failed: {}", stderr.trim()));
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let summary = output.summary(&action.command);
+        if !output.success() {
+            match action.output {
+                // The preview also shows stderr, so it is shown even if the command failed
+                ExternalCommandOutput::Preview => {
+                    self.show_command_preview(&stdout, &stderr);
+                    self.set_failure_message(summary);
+                }
+                // A failed filter shows its error in the preview, as the message line is too short for it
+                ExternalCommandOutput::ReplaceBuffer => {
+                    self.show_command_preview(&stdout, &stderr);
+                    self.set_failure_message(format!("{summary}, buffer unchanged"));
+                }
+                _ => self.set_failure_message(format!("{summary}: {}", stderr.trim())),
+            }
             return Ok(());
         }
 
-        self.start_editing();
+        match action.output {
+            ExternalCommandOutput::ReplaceMark | ExternalCommandOutput::Insert => {
+                self.start_editing();
+                let output_str = stdout.trim_end(); // Remove trailing whitespace/newlines
+                if action.output == ExternalCommandOutput::ReplaceMark
+                    && let Some((start, end)) = mark_range
+                {
+                    self.delete_text_in_range(start, end);
+                    self.cursor = start;
+                    self.set_message(format!(
+                        "Replaced region with command output ({} chars)",
+                        output_str.len()
+                    ));
+                } else {
+                    self.set_message(format!(
+                        "Inserted command output ({} chars)",
+                        output_str.len()
+                    ));
+                }
+                self.clear_mark();
+                self.cursor = self.buffer.insert_str_at(self.cursor, output_str);
+                self.finish_editing();
+            }
+            ExternalCommandOutput::ReplaceBuffer => {
+                if self.apply_line_diff(&stdout) {
+                    self.set_message(format!(
+                        "Replaced buffer with `$ {}` output",
+                        action.command
+                    ));
+                } else {
+                    self.set_message("No changes");
+                }
+            }
+            ExternalCommandOutput::Message => {
+                // The message line only fits one line, so longer output goes to the preview
+                let mut lines = stdout.trim().lines();
+                let first_line = lines.next().unwrap_or_default().to_owned();
+                if lines.next().is_some() {
+                    self.show_command_preview(&stdout, "");
+                    self.set_message(format!("{first_line} (see preview)"));
+                } else {
+                    self.set_message(first_line);
+                }
+            }
+            ExternalCommandOutput::Clipboard => {
+                self.clipboard.write(&stdout).or_fail()?;
+                self.set_message(format!("Copied command output ({} chars)", stdout.len()));
+            }
+            ExternalCommandOutput::Preview => {
+                self.show_command_preview(&stdout, &stderr);
+                self.set_message(summary);
+            }
+            ExternalCommandOutput::Discard => {
+                self.set_message(summary);
+            }
+        }
 
-        let stdout = String::from_utf8_lossy(&output.stdout);
+        Ok(())
+    }
 
-        if let Some(mark_pos) = self.mark.take() {
-            // Replace marked region with output
-            let cursor_pos = self.cursor_position();
-            let (start, end) = if mark_pos <= cursor_pos {
-                (mark_pos, cursor_pos)
-            } else {
-                (cursor_pos, mark_pos)
-            };
+    // Shows stdout and stderr side by side, omitting an empty one
+    fn show_command_preview(&mut self, stdout: &str, stderr: &str) {
+        let pane = |title, text: &str| (!text.is_empty()).then(|| PreviewPane::new(title, text));
+        let left_pane = pane("stdout", stdout);
+        let right_pane = pane("stderr", stderr);
+        if left_pane.is_some() || right_pane.is_some() {
+            self.preview = Some(Preview::new(left_pane, right_pane));
+        }
+    }
 
-            self.start_editing();
+    /// Starts a command in a pty, replacing the output pane (and killing its command, if running).
+    pub fn handle_pty_command(
+        &mut self,
+        action: &PtyCommandAction,
+        context: &str,
+        size: TerminalSize,
+    ) -> orfail::Result<()> {
+        self.finish_editing();
+        self.output = None;
 
-            // Delete the marked region
-            self.delete_text_in_range(start, end);
-            self.cursor = start;
+        let args = self.command_args(&action.args, context);
+        let mut name = action.command.clone();
+        for arg in &args {
+            name.push(' ');
+            name.push_str(arg);
+        }
+        let mut cmd = std::process::Command::new(&action.command);
+        cmd.args(args);
+        cmd.envs(self.command_envs(context));
+        let dir = apply_command_env(&mut cmd, &action.env, &self.path);
+        match PtyProcess::spawn(cmd, size) {
+            Err(e) => self.set_message(e.message),
+            Ok(process) => {
+                self.output = Some(OutputPane::new(name, process, dir));
+                if self.errors.as_ref().is_some_and(|e| e.from_output) {
+                    self.errors = None; // Stale, as it was scanned from the previous output
+                }
+                self.set_message(format!("Running `$ {}` ...", action.command));
+            }
+        }
+        Ok(())
+    }
 
-            // Insert the command output
-            let output_str = stdout.trim_end(); // Remove trailing whitespace/newlines
-            let lines: Vec<&str> = output_str.lines().collect();
+    /// Reads the output of the running pty command.
+    pub fn handle_output_fd_ready(&mut self, fd: RawFd) {
+        let Some(output) = &mut self.output else {
+            return;
+        };
+        match output.handle_fd_ready(fd) {
+            Err(e) => self.set_message(e.message),
+            Ok(false) => {}
+            Ok(true) => {
+                // The output is navigated by "error-next" and "error-prev"
+                let errors = output.scan_errors(&ErrorPattern::defaults());
+                let message = format!("{}, errors: {}", output.summary(), errors.errors.len());
+                self.errors = Some(errors);
+                self.set_message(message);
+            }
+        }
+    }
 
-            if !lines.is_empty() {
-                // Insert first line
-                for ch in lines[0].chars() {
-                    self.cursor = self.buffer.insert_char_at(self.cursor, ch);
-                }
+    pub fn handle_output_close(&mut self) {
+        if let Some(output) = self.output.take()
+            && output.is_running()
+        {
+            // Dropping the pane also kills the command
+            self.set_message("Canceled command");
+        }
+    }
 
-                // Insert subsequent lines with newlines
-                for line in &lines[1..] {
-                    self.cursor = self.buffer.insert_newline_at(self.cursor);
-                    for ch in line.chars() {
-                        self.cursor = self.buffer.insert_char_at(self.cursor, ch);
-                    }
+    /// Builds the error list from the action's file, or from the output pane.
+    pub fn handle_error_list(&mut self, action: &ErrorListAction) -> orfail::Result<()> {
+        let errors = if let Some(file) = &action.file {
+            let dir = action.cwd.as_ref().map(|cwd| resolve_cwd(cwd, &self.path));
+            let file = match &dir {
+                Some(dir) => dir.join(file),
+                None => file.clone(),
+            };
+            match std::fs::read_to_string(&file) {
+                Ok(text) => {
+                    let source = file.display().to_string();
+                    ErrorList::scan(source, &text, &action.patterns, dir.as_deref())
+                }
+                Err(e) => {
+                    self.set_message(format!("Failed to read {}: {e}", file.display()));
+                    return Ok(());
                 }
             }
-
-            self.finish_editing();
-            self.set_message(format!(
-                "Replaced region with command output ({} chars)",
-                output_str.len()
-            ));
+        } else if let Some(output) = &self.output {
+            output.scan_errors(&action.patterns)
         } else {
-            // No marked region, insert output at cursor
-            self.start_editing();
+            self.set_message("No command output");
+            return Ok(());
+        };
 
-            let output_str = stdout.trim_end();
-            let lines: Vec<&str> = output_str.lines().collect();
+        self.set_message(format!(
+            "Errors: {} ({})",
+            errors.errors.len(),
+            errors.source
+        ));
+        self.errors = Some(errors);
+        Ok(())
+    }
 
-            if !lines.is_empty() {
-                // Insert first line
-                for ch in lines[0].chars() {
-                    self.cursor = self.buffer.insert_char_at(self.cursor, ch);
-                }
+    pub fn handle_error_next(&mut self) {
+        self.move_to_error(true);
+    }
 
-                // Insert subsequent lines with newlines
-                for line in &lines[1..] {
-                    self.cursor = self.buffer.insert_newline_at(self.cursor);
-                    for ch in line.chars() {
-                        self.cursor = self.buffer.insert_char_at(self.cursor, ch);
-                    }
-                }
-            }
+    pub fn handle_error_prev(&mut self) {
+        self.move_to_error(false);
+    }
 
-            self.finish_editing();
-            self.set_message(format!(
-                "Inserted command output ({} chars)",
-                output_str.len()
-            ));
+    fn move_to_error(&mut self, forward: bool) {
+        let Some(errors) = &mut self.errors else {
+            self.set_message("No error list");
+            return;
+        };
+        let n = errors.errors.len();
+        let i = match (errors.index, forward) {
+            _ if n == 0 => None,
+            (None, true) => Some(0),
+            (None, false) => Some(n - 1),
+            (Some(i), true) => (i + 1 < n).then_some(i + 1),
+            (Some(i), false) => i.checked_sub(1),
+        };
+        let Some(i) = i else {
+            self.set_message(if forward {
+                "No next error"
+            } else {
+                "No previous error"
+            });
+            return;
+        };
+
+        errors.index = Some(i);
+        let entry = errors.errors[i].clone();
+        match self.restore_anchor(&entry.anchor) {
+            Ok(true) => {}
+            Ok(false) => return,
+            Err(e) => {
+                self.set_message(format!(
+                    "Failed to open {}: {}",
+                    entry.anchor.path.display(),
+                    e.message
+                ));
+                return;
+            }
         }
+        self.set_message(format!("Error {}/{n}: {}", i + 1, entry.message));
+    }
+
+    /// Replaces the buffer text with `text`, editing only the lines that differ.
+    ///
+    /// The change is a single undo step, and the cursor stays on the same logical line where possible.
+    /// Returns `false` if the text is unchanged.
+    fn apply_line_diff(&mut self, text: &str) -> bool {
         self.finish_editing();
+        let cursor = self.cursor;
+        let dirty = self.buffer.dirty;
+        let old_text = self.buffer.text.clone();
+        let replaced = self.buffer.apply_line_diff(text);
+        if replaced.is_empty() {
+            return false;
+        }
 
-        Ok(())
+        let edits = &self.buffer.journal[self.buffer.journal.len() - replaced.len()..];
+        let mut new_cursor = cursor;
+        for edit in edits {
+            if new_cursor.row < edit.start.row || new_cursor.row >= edit.old_end.row {
+                new_cursor = edit.shift_position(new_cursor);
+                continue;
+            }
+
+            // The cursor line was replaced: stay on the most similar line, e.g. one that a formatter re-indented
+            let line = &old_text[cursor.row];
+            new_cursor.row = self
+                .find_similar_row(line, new_cursor.row, edit.start.row..edit.new_end.row)
+                .unwrap_or_else(|| new_cursor.row.min(edit.new_end.row));
+            break;
+        }
+        self.cursor = self.buffer.adjust_to_char_boundary(new_cursor, true);
+
+        self.push_history(cursor, UndoEntry::Lines { replaced, dirty });
+        true
+    }
+
+    // Finds the row in `rows` whose content is the closest to `line`, formerly at `row`
+    fn find_similar_row(&self, line: &TextLine, row: usize, rows: Range<usize>) -> Option<usize> {
+        // Whitespace is ignored as formatters mostly change it
+        let non_whitespace = |line: &TextLine| {
+            line.0
+                .iter()
+                .copied()
+                .filter(|c| !c.is_whitespace())
+                .collect::<String>()
+        };
+        let target = non_whitespace(line);
+        rows.filter(|&r| non_whitespace(&self.buffer.text[r]) == target)
+            .min_by_key(|&r| r.abs_diff(row))
     }
 
     pub fn current_cursor_anchor(&self) -> CursorAnchor {
+        self.anchor_at(self.cursor)
+    }
+
+    pub fn anchor_at(&self, pos: TextPosition) -> CursorAnchor {
         CursorAnchor {
             path: self.path.clone(),
-            line: NonZeroUsize::MIN.saturating_add(self.cursor.row),
+            line: NonZeroUsize::MIN.saturating_add(pos.row),
             char: NonZeroUsize::MIN.saturating_add(
                 self.buffer
-                    .char_index_at_col(self.cursor.row, self.cursor.col)
+                    .char_index_at_col(pos.row, pos.col)
                     .unwrap_or_default(),
             ),
         }
     }
 
+    /// Takes the edits made to the buffer since the last call, shifting the mark and the cursors accordingly.
+    pub fn take_edit_journal(&mut self) -> Vec<TextEdit> {
+        self.sync_cursors();
+        self.cursors_synced = 0;
+        let edits = self.buffer.take_journal();
+        if let Some(mark) = &mut self.mark {
+            for edit in &edits {
+                *mark = edit.shift_position(*mark);
+            }
+        }
+        edits
+    }
+
     pub fn handle_view_recenter(&mut self) {
         self.finish_editing();
         self.recenter_viewport = true;
@@ -763,17 +1608,26 @@ impl State {
         if cursor_pos.col >= current_line_cols {
             // Cursor is at or past end of line - delete the newline (merge with next line)
             if cursor_pos.row < self.buffer.rows().saturating_sub(1)
-                && let Some(next_line) = self.buffer.text.get(cursor_pos.row + 1).cloned() {
-                    // Copy the newline to clipboard
-                    self.clipboard.write("\n").or_fail()?;
-
-                    self.buffer.text.remove(cursor_pos.row + 1);
-                    if let Some(current_line) = self.buffer.text.get_mut(cursor_pos.row) {
-                        current_line.extend_from_line(next_line);
-                        self.buffer.dirty = true;
-                    }
-                    self.set_message("Killed newline");
+                && let Some(next_line) = self.buffer.text.get(cursor_pos.row + 1).cloned()
+            {
+                // Copy the newline to clipboard
+                self.clipboard.write("\n").or_fail()?;
+
+                self.buffer.text.remove(cursor_pos.row + 1);
+                if let Some(current_line) = self.buffer.text.get_mut(cursor_pos.row) {
+                    current_line.extend_from_line(next_line);
+                    self.buffer.dirty = true;
                 }
+                self.buffer.record_edit(TextEdit {
+                    start: cursor_pos,
+                    old_end: TextPosition {
+                        row: cursor_pos.row + 1,
+                        col: 0,
+                    },
+                    new_end: cursor_pos,
+                });
+                self.set_message("Killed newline");
+            }
         } else {
             // Delete from cursor to end of line and copy to clipboard
             if let Some(line) = self.buffer.text.get_mut(cursor_pos.row) {
@@ -789,6 +1643,14 @@ impl State {
                     // Delete the text
                     line.0.truncate(char_index);
                     self.buffer.dirty = true;
+                    self.buffer.record_edit(TextEdit {
+                        start: cursor_pos,
+                        old_end: TextPosition {
+                            row: cursor_pos.row,
+                            col: current_line_cols,
+                        },
+                        new_end: cursor_pos,
+                    });
 
                     self.set_message(format!("Killed {} characters", killed_text.len()));
                 } else {
@@ -816,26 +1678,31 @@ impl State {
             return;
         };
         grep.action.forward = true;
+        let is_project = matches!(grep.action.scope, GrepScope::Project { .. });
 
         self.finish_editing();
 
+        if is_project {
+            self.move_to_project_hit(true);
+            return;
+        }
+
         let current_pos = self.cursor_position();
+        let n = self.highlight.items.len();
 
         // Find the next highlight item after the current cursor position
-        if let Some(next_item) = self
+        if let Some(i) = self
             .highlight
             .items
             .iter()
-            .find(|item| item.start_position > current_pos)
+            .position(|item| item.start_position > current_pos)
         {
-            self.cursor = next_item.start_position;
-            self.recenter_viewport = true;
-            self.set_message("Moved to next grep hit");
-        } else if let Some(first_item) = self.highlight.items.first() {
+            self.move_to_grep_hit(i);
+            self.set_message(format!("Hit {}/{n}", i + 1));
+        } else if n > 0 {
             // Wrap around to the first item
-            self.cursor = first_item.start_position;
-            self.recenter_viewport = true;
-            self.set_message("Wrapped to first grep hit");
+            self.move_to_grep_hit(0);
+            self.set_message(format!("Wrapped to first hit (1/{n})"));
         }
     }
 
@@ -844,30 +1711,186 @@ impl State {
             return;
         };
         grep.action.forward = false;
+        let is_project = matches!(grep.action.scope, GrepScope::Project { .. });
 
         self.finish_editing();
 
+        if is_project {
+            self.move_to_project_hit(false);
+            return;
+        }
+
         let current_pos = self.cursor_position();
+        let n = self.highlight.items.len();
 
         // Find the previous highlight item before the current cursor position
-        if let Some(prev_item) = self
+        if let Some(i) = self
             .highlight
             .items
             .iter()
-            .rev()
-            .find(|item| item.start_position < current_pos)
+            .rposition(|item| item.start_position < current_pos)
         {
-            self.cursor = prev_item.start_position;
-            self.recenter_viewport = true;
-            self.set_message("Moved to previous grep hit");
-        } else if let Some(last_item) = self.highlight.items.last() {
+            self.move_to_grep_hit(i);
+            self.set_message(format!("Hit {}/{n}", i + 1));
+        } else if n > 0 {
             // Wrap around to the last item
-            self.cursor = last_item.start_position;
+            self.move_to_grep_hit(n - 1);
+            self.set_message(format!("Wrapped to last hit ({n}/{n})"));
+        }
+    }
+
+    /// Opens the results of the last project search as the buffer, at the current hit if any.
+    pub fn handle_grep_results_open(&mut self) -> orfail::Result<()> {
+        let path = results_file_path();
+        if !path.is_file() {
+            self.set_message("No grep results");
+            return Ok(());
+        }
+        let index = self.grep_mode.as_ref().and_then(|g| g.project_hit_index);
+        let anchor = CursorAnchor {
+            path,
+            line: NonZeroUsize::MIN.saturating_add(index.unwrap_or_default()),
+            char: NonZeroUsize::MIN,
+        };
+        if self.restore_anchor(&anchor).or_fail()? {
+            self.set_message("Opened grep results");
+        }
+        Ok(())
+    }
+
+    pub fn handle_grep_first_hit(&mut self) {
+        self.move_to_grep_hit_end(true);
+    }
+
+    pub fn handle_grep_last_hit(&mut self) {
+        self.move_to_grep_hit_end(false);
+    }
+
+    fn move_to_grep_hit_end(&mut self, first: bool) {
+        self.finish_editing();
+        let Some(grep) = &self.grep_mode else {
+            return;
+        };
+
+        let is_project = matches!(grep.action.scope, GrepScope::Project { .. });
+        let n = if is_project {
+            grep.project_hits.len()
+        } else {
+            self.highlight.items.len()
+        };
+        if n == 0 {
+            self.set_message("No grep hits available");
+            return;
+        }
+
+        let i = if first { 0 } else { n - 1 };
+        if is_project {
+            self.jump_to_project_hit(i, false);
+        } else {
+            self.move_to_grep_hit(i);
+            self.set_message(format!("Hit {}/{n}", i + 1));
+        }
+    }
+
+    fn move_to_grep_hit(&mut self, i: usize) {
+        if let Some(item) = self.highlight.items.get(i) {
+            self.cursor = item.start_position;
             self.recenter_viewport = true;
-            self.set_message("Wrapped to last grep hit");
         }
     }
 
+    /// Returns the 1-origin index of the hit under the cursor (if any) and the total number of hits.
+    pub fn grep_hit_counter(&self) -> Option<(Option<usize>, usize)> {
+        let grep = self.grep_mode.as_ref()?;
+        if let GrepScope::Project { .. } = grep.action.scope {
+            return Some((
+                grep.project_hit_index.map(|i| i + 1),
+                grep.project_hits.len(),
+            ));
+        }
+
+        let current_pos = self.cursor_position();
+        let current = self
+            .highlight
+            .items
+            .iter()
+            .position(|item| item.start_position <= current_pos && current_pos < item.end_position)
+            .map(|i| i + 1);
+        Some((current, self.highlight.items.len()))
+    }
+
+    pub fn has_grep_hits(&self) -> bool {
+        !self.highlight.items.is_empty()
+            || self
+                .grep_mode
+                .as_ref()
+                .is_some_and(|g| !g.project_hits.is_empty())
+    }
+
+    fn move_to_project_hit(&mut self, forward: bool) {
+        let Some(grep) = &mut self.grep_mode else {
+            return;
+        };
+        let n = grep.project_hits.len();
+        if n == 0 {
+            self.set_message("No grep hits available");
+            return;
+        }
+
+        let (i, wrapped) = match (grep.project_hit_index, forward) {
+            (None, true) => (0, false),
+            (None, false) => (n - 1, false),
+            (Some(i), true) => ((i + 1) % n, i + 1 == n),
+            (Some(i), false) => ((i + n - 1) % n, i == 0),
+        };
+        self.jump_to_project_hit(i, wrapped);
+    }
+
+    fn jump_to_project_hit(&mut self, i: usize, wrapped: bool) {
+        let Some(grep) = &mut self.grep_mode else {
+            return;
+        };
+        let n = grep.project_hits.len();
+        grep.project_hit_index = Some(i);
+        let anchor = grep.project_hits[i].anchor.clone();
+
+        match self.restore_anchor(&anchor) {
+            Ok(true) => {}
+            Ok(false) => return,
+            Err(e) => {
+                self.set_message(format!(
+                    "Failed to open {}: {}",
+                    anchor.path.display(),
+                    e.message
+                ));
+                return;
+            }
+        }
+        if let Some(grep) = &self.grep_mode {
+            self.highlight = grep.highlight_in(&self.buffer);
+        }
+        if anchor.char == NonZeroUsize::MIN
+            && let Some(item) = self
+                .highlight
+                .items
+                .iter()
+                .find(|item| item.start_position.row == self.cursor.row)
+        {
+            // The command did not report a column (e.g., `grep -rn`)
+            self.cursor = item.start_position;
+        }
+        let prefix = if wrapped {
+            if i == 0 {
+                "Wrapped to first hit"
+            } else {
+                "Wrapped to last hit"
+            }
+        } else {
+            "Hit"
+        };
+        self.set_message(format!("{prefix} {}/{n}: {}", i + 1, anchor.to_relative()));
+    }
+
     pub fn handle_cursor_skip_spaces(&mut self) {
         self.finish_editing();
 
@@ -945,6 +1968,22 @@ impl State {
     }
 
     pub fn handle_goto_line(&mut self) -> orfail::Result<()> {
+        if let Some(text) = self.prompt_value.clone() {
+            let anchor = match CursorAnchor::parse_for_goto(&text, &self.path) {
+                Some(anchor) => anchor,
+                // Bookmark names are also accepted
+                None => match self.bookmarks.get(text.trim()).or_fail()? {
+                    Some(anchor) => anchor,
+                    None => {
+                        self.set_message(format!("Invalid goto anchor: {text}"));
+                        return Ok(());
+                    }
+                },
+            };
+            self.restore_anchor(&anchor).or_fail()?;
+            return Ok(());
+        }
+
         let text = self.clipboard.read().or_fail()?;
         let Some(anchor) = CursorAnchor::parse_for_goto(&text, &self.path) else {
             self.set_message("No goto anchor in the clipboard");
@@ -955,6 +1994,131 @@ impl State {
         Ok(())
     }
 
+    /// Jumps to the location at the start of the cursor line, such as a line of the grep results.
+    pub fn handle_goto_cursor_line(&mut self) -> orfail::Result<()> {
+        let line = self
+            .buffer
+            .text
+            .get(self.cursor.row)
+            .map(|line| line.0.iter().collect::<String>())
+            .unwrap_or_default();
+        let Some(anchor) =
+            CursorAnchor::parse_for_goto(&line, &self.path).filter(|a| a.path.is_file())
+        else {
+            self.set_message("No location on the cursor line");
+            return Ok(());
+        };
+        if self.restore_anchor(&anchor).or_fail()? {
+            self.set_message(format!("Goto: {}", anchor.to_relative()));
+        }
+        Ok(())
+    }
+
+    pub fn handle_goto(&mut self, action: &GotoAction) -> orfail::Result<()> {
+        let Some(anchor) = CursorAnchor::parse_for_goto(&action.anchor, &self.path) else {
+            self.set_failure_message(format!("Invalid goto anchor: {}", action.anchor));
+            return Ok(());
+        };
+        if !is_same_path(&self.path, &anchor.path) && !anchor.path.is_file() {
+            self.set_failure_message(format!("No such file: {}", anchor.path.display()));
+            return Ok(());
+        }
+        if self.restore_anchor(&anchor).or_fail()? {
+            self.set_message(format!("Goto: {}", anchor.to_relative()));
+        }
+        Ok(())
+    }
+
+    pub fn handle_bookmark_set(&mut self, action: &BookmarkAction) -> orfail::Result<()> {
+        let Some(name) = self.bookmark_name(action) else {
+            return Ok(());
+        };
+        let anchor = self.current_cursor_anchor();
+        self.bookmarks.set(&name, anchor.clone()).or_fail()?;
+        self.set_message(format!("Bookmark {name}: {}", anchor.to_relative()));
+        Ok(())
+    }
+
+    pub fn handle_bookmark_jump(&mut self, action: &BookmarkAction) -> orfail::Result<()> {
+        let Some(name) = self.bookmark_name(action) else {
+            return Ok(());
+        };
+        let Some(anchor) = self.bookmarks.get(&name).or_fail()? else {
+            self.set_message(format!("No such bookmark: {name}"));
+            return Ok(());
+        };
+        if self.restore_anchor(&anchor).or_fail()? {
+            self.set_message(format!("Bookmark {name}: {}", anchor.to_relative()));
+        }
+        Ok(())
+    }
+
+    /// Previews the bookmark file, if any bookmark exists.
+    pub fn handle_bookmark_list(&mut self) -> orfail::Result<()> {
+        let bookmarks = self.bookmarks.list().or_fail()?;
+        if bookmarks.is_empty() {
+            self.set_message("No bookmarks");
+            return Ok(());
+        }
+        self.set_message(format!("Bookmarks: {}", bookmarks.len()));
+        let spec = FilePreviewSpec {
+            left_pane: Some(FilePreviewPaneSpec {
+                file: self.bookmarks.file_path.clone(),
+            }),
+            right_pane: None,
+        };
+        self.preview = Some(Preview::load(&spec).or_fail()?);
+        Ok(())
+    }
+
+    pub fn handle_preview_scroll_up(&mut self) {
+        match &mut self.preview {
+            Some(preview) => preview.cursor_up(),
+            None => self.set_message("No preview"),
+        }
+    }
+
+    pub fn handle_preview_scroll_down(&mut self) {
+        match &mut self.preview {
+            Some(preview) => preview.cursor_down(),
+            None => self.set_message("No preview"),
+        }
+    }
+
+    pub fn handle_preview_copy_line(&mut self) -> orfail::Result<()> {
+        let Some(line) = self
+            .preview
+            .as_ref()
+            .and_then(|p| p.cursor_line())
+            .map(str::to_owned)
+        else {
+            self.set_message("No preview line");
+            return Ok(());
+        };
+        self.clipboard.write(&line).or_fail()?;
+        self.set_message(format!(
+            "Copied preview line ({} chars)",
+            line.chars().count()
+        ));
+        Ok(())
+    }
+
+    fn bookmark_name(&mut self, action: &BookmarkAction) -> Option<String> {
+        let name = match (&action.name, &self.prompt_value) {
+            (Some(name), _) => name.clone(),
+            (None, Some(value)) => value.trim().to_owned(),
+            (None, None) => {
+                self.set_message("No bookmark name");
+                return None;
+            }
+        };
+        if !Bookmarks::is_valid_name(&name) {
+            self.set_message(format!("Invalid bookmark name: {name:?}"));
+            return None;
+        }
+        Some(name)
+    }
+
     pub fn handle_cursor_left_skip_chars(&mut self, skip_chars: &str) {
         self.finish_editing();
 
@@ -1043,8 +2207,7 @@ impl State {
 
         match grep.next_query() {
             Ok(Some(query)) => {
-                grep.query = query.chars().collect();
-                grep.cursor = grep.query.len();
+                grep.query.set_text(&query);
                 self.regrep();
                 self.set_message("Next query");
             }
@@ -1065,8 +2228,7 @@ impl State {
 
         match grep.prev_query() {
             Ok(Some(query)) => {
-                grep.query = query.chars().collect();
-                grep.cursor = grep.query.len();
+                grep.query.set_text(&query);
                 self.regrep();
                 self.set_message("Previous query");
             }
@@ -1079,6 +2241,129 @@ impl State {
         }
     }
 
+    pub fn handle_query_word_left(&mut self) {
+        self.edit_query(|query| {
+            query.move_word_left();
+            false
+        });
+    }
+
+    pub fn handle_query_word_right(&mut self) {
+        self.edit_query(|query| {
+            query.move_word_right();
+            false
+        });
+    }
+
+    pub fn handle_query_word_delete_backward(&mut self) {
+        self.edit_query(|query| query.delete_word_backward());
+    }
+
+    pub fn handle_query_word_delete_forward(&mut self) {
+        self.edit_query(|query| query.delete_word_forward());
+    }
+
+    pub fn handle_query_kill(&mut self) {
+        self.edit_query(|query| query.kill());
+    }
+
+    pub fn handle_query_transpose(&mut self) {
+        self.edit_query(|query| query.transpose());
+    }
+
+    // Applies `f` to the prompt input or the grep query (re-running the search if the query has changed)
+    fn edit_query<F>(&mut self, f: F)
+    where
+        F: FnOnce(&mut LineEditor) -> bool,
+    {
+        if let Some(prompt) = &mut self.prompt {
+            f(&mut prompt.input);
+            return;
+        }
+
+        let Some(grep) = &mut self.grep_mode else {
+            self.set_message("Not in grep mode");
+            return;
+        };
+        if f(&mut grep.query) {
+            self.regrep();
+        }
+    }
+
+    /// Appends the word under the cursor to the grep query.
+    ///
+    /// If the cursor is on a hit, the query is extended up to the end of the word following the hit instead.
+    pub fn handle_grep_yank_word(&mut self) {
+        let Some(grep) = &self.grep_mode else {
+            self.set_message("Not in grep mode");
+            return;
+        };
+        if grep.is_searching() {
+            // The hits are stale until the search for the current query finishes
+            self.set_message("Searching...");
+            return;
+        }
+        let Some(line) = self.buffer.text.get(self.cursor.row) else {
+            self.set_message("No word at cursor");
+            return;
+        };
+
+        let mut start = line.char_index_at_col(self.cursor.col);
+        match self.grep_hit_at_cursor() {
+            Some(hit) if !grep.query.is_empty() && hit.end_position.row == self.cursor.row => {
+                start = line.char_index_at_col(hit.end_position.col);
+            }
+            _ => {
+                while start > 0 && is_word_char(line.0[start - 1]) {
+                    start -= 1;
+                }
+            }
+        }
+        let mut end = start;
+        while end < line.len() && !is_word_char(line.0[end]) {
+            end += 1;
+        }
+        while end < line.len() && is_word_char(line.0[end]) {
+            end += 1;
+        }
+        if start == end {
+            self.set_message("No word at cursor");
+            return;
+        }
+
+        let word = line.0[start..end].iter().collect::<String>();
+        let grep = self.grep_mode.as_mut().expect("infallible");
+        grep.query.move_end();
+        grep.insert_literal(&word);
+        self.regrep();
+    }
+
+    pub fn handle_prompt_next_input(&mut self) {
+        let Some(prompt) = &mut self.prompt else {
+            self.set_message("No active prompt");
+            return;
+        };
+
+        match prompt.next_input() {
+            Ok(Some(input)) => prompt.input.set_text(&input),
+            Ok(None) => self.set_message("No next input"),
+            Err(e) => self.set_message(format!("Error loading next input: {}", e)),
+        }
+    }
+
+    pub fn handle_prompt_prev_input(&mut self) {
+        let Some(prompt) = &mut self.prompt else {
+            self.set_message("No active prompt");
+            return;
+        };
+
+        match prompt.prev_input() {
+            Ok(Some(input)) => prompt.input.set_text(&input),
+            Ok(None) => self.set_message("No previous input"),
+            Err(e) => self.set_message(format!("Error loading previous input: {}", e)),
+        }
+    }
+
     pub fn handle_grep_replace_hit(&mut self) -> orfail::Result<()> {
         if self.grep_mode.is_none() {
             self.set_message("Not in grep mode");
@@ -1091,80 +2376,389 @@ impl State {
         }
 
         // Find the current hit that contains the cursor
-        let current_pos = self.cursor_position();
-        let current_hit = self
-            .highlight
-            .items
-            .iter()
+        let Some(hit) = self.grep_hit_at_cursor() else {
+            self.set_message("Cursor is not on a grep hit");
+            return Ok(());
+        };
+
+        // Get clipboard content
+        let Some((template, regex)) = self.replacement_template().or_fail()? else {
+            return Ok(());
+        };
+
+        self.finish_editing();
+        self.start_editing();
+        let edit = self.replace_grep_hit(hit, &template, regex.as_ref());
+        self.cursor = edit.new_end;
+        self.finish_editing();
+
+        self.set_message("Replaced hit");
+        Ok(())
+    }
+
+    pub fn handle_grep_replace_all(&mut self) -> orfail::Result<()> {
+        let Some(grep) = &self.grep_mode else {
+            self.set_message("Not in grep mode");
+            return Ok(());
+        };
+
+        let hits = grep
+            .target_hits(&self.highlight)
             .copied()
-            .find(|item| item.start_position <= current_pos && current_pos < item.end_position);
+            .collect::<Vec<_>>();
+        if hits.is_empty() {
+            self.set_message("No grep hits to replace");
+            return Ok(());
+        }
 
-        let Some(hit) = current_hit else {
+        let Some((template, regex)) = self.replacement_template().or_fail()? else {
+            return Ok(());
+        };
+
+        // Replace from the last hit so that the positions of the remaining hits stay valid
+        self.finish_editing();
+        self.start_editing();
+        for hit in hits.iter().rev() {
+            self.replace_grep_hit(*hit, &template, regex.as_ref());
+        }
+        self.finish_editing();
+
+        self.set_message(format!("Replaced {} hits", hits.len()));
+        Ok(())
+    }
+
+    pub fn handle_grep_replace_confirm(&mut self) {
+        let Some(grep) = &mut self.grep_mode else {
+            self.set_message("Not in grep mode");
+            return;
+        };
+
+        let first_hit = grep
+            .target_hits(&self.highlight)
+            .find(|item| self.cursor <= item.start_position)
+            .or_else(|| grep.target_hits(&self.highlight).next())
+            .copied();
+        let Some(hit) = first_hit else {
+            self.set_message("No grep hits to replace");
+            return;
+        };
+        grep.confirm_replaced = Some(0);
+
+        self.finish_editing();
+        self.cursor = hit.start_position;
+        self.recenter_viewport = true;
+        self.set_message("Replace this hit? (y: yes, n: no, a: all, q: quit)");
+    }
+
+    pub fn handle_grep_confirm_yes(&mut self) -> orfail::Result<()> {
+        if !self.is_confirming_replacement() {
+            return Ok(());
+        }
+        let Some(hit) = self.grep_hit_at_cursor() else {
             self.set_message("Cursor is not on a grep hit");
             return Ok(());
         };
+        let Some((template, regex)) = self.replacement_template().or_fail()? else {
+            return Ok(());
+        };
 
-        // Get clipboard content
-        let replacement_text = self.clipboard.read().or_fail()?;
-        if replacement_text.is_empty() {
-            self.set_message("Clipboard is empty");
+        // The whole confirm session is a single undo group
+        self.start_editing();
+        let edit = self.replace_grep_hit(hit, &template, regex.as_ref());
+        if let Some(count) = self
+            .grep_mode
+            .as_mut()
+            .and_then(|g| g.confirm_replaced.as_mut())
+        {
+            *count += 1;
+        }
+        self.move_to_next_confirm_hit(edit.new_end);
+        Ok(())
+    }
+
+    pub fn handle_grep_confirm_no(&mut self) {
+        if !self.is_confirming_replacement() {
+            return;
+        }
+        let next = TextPosition {
+            row: self.cursor.row,
+            col: self.cursor.col + 1,
+        };
+        self.move_to_next_confirm_hit(next);
+    }
+
+    pub fn handle_grep_confirm_all(&mut self) -> orfail::Result<()> {
+        let Some(grep) = &self.grep_mode else {
+            return Ok(());
+        };
+        if grep.confirm_replaced.is_none() {
+            self.set_message("Not confirming replacements");
             return Ok(());
         }
 
+        let hits = grep
+            .target_hits(&self.highlight)
+            .filter(|item| self.cursor <= item.start_position)
+            .copied()
+            .collect::<Vec<_>>();
+        let Some((template, regex)) = self.replacement_template().or_fail()? else {
+            return Ok(());
+        };
+
         self.start_editing();
+        for hit in hits.iter().rev() {
+            self.replace_grep_hit(*hit, &template, regex.as_ref());
+        }
+        if let Some(count) = self
+            .grep_mode
+            .as_mut()
+            .and_then(|g| g.confirm_replaced.as_mut())
+        {
+            *count += hits.len();
+        }
+        self.handle_grep_confirm_quit();
+        Ok(())
+    }
 
-        // Delete the current hit
-        self.delete_text_in_range(hit.start_position, hit.end_position);
+    pub fn handle_grep_confirm_quit(&mut self) {
+        let Some(count) = self
+            .grep_mode
+            .as_mut()
+            .and_then(|g| g.confirm_replaced.take())
+        else {
+            return;
+        };
+        self.finish_editing();
+        self.set_message(format!("Replaced {count} hits"));
+    }
+
+    fn is_confirming_replacement(&mut self) -> bool {
+        let confirming = self
+            .grep_mode
+            .as_ref()
+            .is_some_and(|g| g.confirm_replaced.is_some());
+        if !confirming {
+            self.set_message("Not confirming replacements");
+        }
+        confirming
+    }
 
-        // Move cursor to the start of the deleted hit
+    fn move_to_next_confirm_hit(&mut self, from: TextPosition) {
+        let Some(grep) = &self.grep_mode else {
+            return;
+        };
+        let next_hit = grep
+            .target_hits(&self.highlight)
+            .find(|item| from <= item.start_position)
+            .copied();
+        let Some(hit) = next_hit else {
+            self.handle_grep_confirm_quit();
+            return;
+        };
         self.cursor = hit.start_position;
+        self.recenter_viewport = true;
+        self.set_message("Replace this hit? (y: yes, n: no, a: all, q: quit)");
+    }
 
-        // Insert the clipboard content
-        let lines: Vec<&str> = replacement_text.lines().collect();
+    fn grep_hit_at_cursor(&self) -> Option<HighlightItem> {
+        let current_pos = self.cursor_position();
+        self.highlight
+            .items
+            .iter()
+            .copied()
+            .find(|item| item.start_position <= current_pos && current_pos < item.end_position)
+    }
 
-        if lines.is_empty() {
-            // Empty replacement
-            self.set_message("Replaced hit with empty text");
-        } else if lines.len() == 1 {
-            // Single line replacement
-            let line = lines[0];
-            for ch in line.chars() {
-                self.cursor = self.buffer.insert_char_at(self.cursor, ch);
+    // Reads the replacement text from the prompt input or the clipboard,
+    // together with the regex used to expand `\N`
+    fn replacement_template(&mut self) -> orfail::Result<Option<(String, Option<Regex>)>> {
+        // An empty prompt input is allowed so that hits can be deleted
+        let template = match &self.prompt_value {
+            Some(value) => value.clone(),
+            None => self.clipboard.read().or_fail()?,
+        };
+        if template.is_empty() && self.prompt_value.is_none() {
+            self.set_message("Clipboard is empty");
+            return Ok(None);
+        }
+
+        let regex = if template.contains('\\') {
+            match self.grep_mode.as_ref().and_then(|g| g.capture_regex()) {
+                Some(Ok(regex)) => Some(regex),
+                Some(Err(e)) => {
+                    self.set_message(format!("Cannot expand capture groups: {}", e.message));
+                    return Ok(None);
+                }
+                None if has_capture_reference(&template) => {
+                    self.set_message(
+                        "Capture groups can only be expanded with the builtin grep engine",
+                    );
+                    return Ok(None);
+                }
+                None => None,
             }
-            self.set_message(format!("Replaced hit with {} characters", line.len()));
         } else {
-            // Multi-line replacement
-            let mut total_chars = 0;
+            None
+        };
+        Ok(Some((template, regex)))
+    }
 
-            // Insert first line
-            for ch in lines[0].chars() {
-                self.cursor = self.buffer.insert_char_at(self.cursor, ch);
-                total_chars += 1;
-            }
+    // Replaces a hit while keeping the other hits and the mark region in place
+    fn replace_grep_hit(
+        &mut self,
+        hit: HighlightItem,
+        template: &str,
+        regex: Option<&Regex>,
+    ) -> TextEdit {
+        let replacement = regex
+            .and_then(|regex| {
+                let line = &self.buffer.text.get(hit.start_position.row)?.0;
+                let start = self
+                    .buffer
+                    .char_index_at_col(hit.start_position.row, hit.start_position.col)?;
+                let text = line.iter().collect::<String>();
+                let offset = line[..start].iter().map(|c| c.len_utf8()).sum();
+                let captures = regex
+                    .captures_at(&text, offset)
+                    .filter(|c| c.get(0).is_some_and(|m| m.start() == offset))?;
+                Some(expand_replacement(template, &captures))
+            })
+            .unwrap_or_else(|| template.to_owned());
 
-            // Insert newline and subsequent lines
-            for line in &lines[1..] {
-                self.cursor = self.buffer.insert_newline_at(self.cursor);
-                total_chars += 1; // Count the newline
+        self.delete_text_in_range(hit.start_position, hit.end_position);
+        let new_end = self.buffer.insert_str_at(hit.start_position, &replacement);
+        self.buffer.dirty = true;
 
-                for ch in line.chars() {
-                    self.cursor = self.buffer.insert_char_at(self.cursor, ch);
-                    total_chars += 1;
-                }
-            }
+        let edit = TextEdit {
+            start: hit.start_position,
+            old_end: hit.end_position,
+            new_end,
+        };
+        self.highlight
+            .items
+            .retain(|item| item.start_position != hit.start_position);
+        for item in &mut self.highlight.items {
+            item.start_position = edit.shift_position(item.start_position);
+            item.end_position = edit.shift_position(item.end_position);
+        }
+        if let Some((start, end)) = self.grep_mode.as_mut().and_then(|g| g.mark_region.as_mut()) {
+            *start = edit.shift_position(*start);
+            *end = edit.shift_position(*end);
+        }
+        self.cursor = edit.shift_position(self.cursor);
+        edit
+    }
+}
 
-            self.set_message(format!(
-                "Replaced hit with {} characters across {} lines",
-                total_chars,
-                lines.len()
-            ));
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::action::GrepAction;
+
+    fn state(name: &str, text: &str) -> State {
+        let path = std::env::temp_dir().join(format!("kk-test-{}-{name}", std::process::id()));
+        std::fs::write(&path, text).expect("write");
+        let state = State::new(path.clone()).expect("load");
+        let _ = std::fs::remove_file(path);
+        state
+    }
+
+    fn pos(row: usize, col: usize) -> TextPosition {
+        TextPosition { row, col }
+    }
+
+    fn key(ch: char) -> tuinix::KeyInput {
+        tuinix::KeyInput {
+            ctrl: false,
+            alt: false,
+            code: KeyCode::Char(ch),
         }
+    }
 
-        self.finish_editing();
+    #[test]
+    fn insert_and_delete_at_cursors() {
+        let mut state = state("insert-and-delete", "a1 a2\na3\n");
+        state.cursor = pos(0, 1);
+        state.cursors = vec![pos(0, 4), pos(1, 1)];
+
+        state.handle_char_insert(key('x'));
+        assert_eq!(state.buffer.to_single_text(), "ax1 ax2\nax3\n");
+        assert_eq!(state.cursor, pos(0, 2));
+        assert_eq!(state.cursors, [pos(0, 6), pos(1, 2)]);
+
+        state.handle_char_delete_backward();
+        state.handle_char_delete_backward();
+        assert_eq!(state.buffer.to_single_text(), "1 2\n3\n");
+        assert_eq!(state.cursor, pos(0, 0));
+        assert_eq!(state.cursors, [pos(0, 2), pos(1, 0)]);
+    }
 
-        // Re-run grep to update highlights after the replacement
-        self.regrep();
+    #[test]
+    fn newline_at_cursors_on_one_line() {
+        let mut state = state("newline", "abc\n");
+        state.cursor = pos(0, 1);
+        state.cursors = vec![pos(0, 2)];
 
-        Ok(())
+        state.handle_newline_insert();
+        assert_eq!(state.buffer.to_single_text(), "a\nb\nc\n");
+        assert_eq!(state.cursor, pos(1, 0));
+        assert_eq!(state.cursors, [pos(2, 0)]);
+    }
+
+    #[test]
+    fn cursors_merge_after_deletion() {
+        let mut state = state("merge", "ab\n");
+        state.cursor = pos(0, 2);
+        state.cursors = vec![pos(0, 1)];
+
+        // Both characters are deleted, leaving the two cursors at the same position
+        state.handle_char_delete_backward();
+        assert_eq!(state.buffer.to_single_text(), "\n");
+        assert_eq!(state.cursor, pos(0, 0));
+        assert!(state.cursors.is_empty());
+    }
+
+    fn start_grep(state: &mut State, json: &str) {
+        let json = nojson::RawJson::parse(json).expect("valid json");
+        let action = GrepAction::try_from(json.value()).expect("valid grep action");
+        state.grep_mode = Some(GrepMode::new(action, None, "@grep"));
+    }
+
+    fn query(state: &State) -> String {
+        state.grep_mode.as_ref().expect("grep mode").query.to_text()
+    }
+
+    #[test]
+    fn yank_words_into_query() {
+        let mut state = state(
+            "yank-words",
+            "let x = foo_bar.baz();
+",
+        );
+        state.cursor = pos(0, 11);
+        start_grep(&mut state, r#"{"engine": "builtin"}"#);
+
+        state.handle_grep_yank_word();
+        assert_eq!(query(&state), "foo_bar");
+
+        // On a hit, the query is extended by the word following it
+        state.handle_grep_yank_word();
+        assert_eq!(query(&state), "foo_bar.baz");
+    }
+
+    #[test]
+    fn yank_words_into_regex_query() {
+        let mut state = state(
+            "yank-regex-words",
+            "a.b(c)
+",
+        );
+        start_grep(&mut state, r#"{"engine": "builtin", "mode": "regex"}"#);
+
+        state.handle_grep_yank_word();
+        state.handle_grep_yank_word();
+        assert_eq!(query(&state), r"a\.b");
+        assert_eq!(state.highlight.items.len(), 1);
     }
 }
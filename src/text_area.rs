@@ -45,7 +45,11 @@ impl TextAreaRenderer {
         line_row: usize,
     ) -> orfail::Result<()> {
         // Calculate marked region for this line if mark is active
-        let marked_region = if let Some(mark_pos) = state.mark {
+        let marked_region = if let Some((top_left, bottom_right)) = state.rect_region() {
+            (top_left.row..=bottom_right.row)
+                .contains(&line_row)
+                .then_some((top_left.col, bottom_right.col))
+        } else if let Some(mark_pos) = state.mark {
             let cursor_pos = state.cursor_position();
             self.calculate_line_marked_region(mark_pos, cursor_pos, line_row)
         } else {
@@ -60,9 +64,11 @@ impl TextAreaRenderer {
                     col: current_col,
                 };
 
-                let is_marked = marked_region
-                    .as_ref()
-                    .is_some_and(|(start, end)| current_col >= *start && current_col < *end);
+                // Wide characters are only marked if they fit entirely within the region
+                let is_marked = marked_region.as_ref().is_some_and(|(start, end)| {
+                    current_col >= *start
+                        && current_col + mame::terminal::char_cols(ch).max(1) <= *end
+                });
                 let is_highlighted = state.highlight.contains(pos);
 
                 let mut style = TerminalStyle::new();
@@ -72,7 +78,7 @@ impl TextAreaRenderer {
                 if is_highlighted {
                     style = style.bg_color(tuinix::TerminalColor::new(220, 220, 220));
                 }
-                if pos == state.cursor {
+                if pos == state.cursor || state.cursors.contains(&pos) {
                     if state.grep_mode.is_some() {
                         style = style.underline().bold();
                     } else {